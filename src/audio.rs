@@ -1,7 +1,8 @@
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use crate::http_client::send_with_retries;
+use crate::ledger::{self, Purpose};
+use crate::llm::endpoint::openai_target;
 use reqwest::multipart::{Form, Part};
 use serde::Deserialize;
-use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
@@ -19,8 +20,8 @@ pub enum TranscriptionFromFilePathError {
 
 #[derive(Error, Debug)]
 pub enum TranscriptionError {
-    #[error("authorization error: OPENAI_API_KEY is not set")]
-    AuthorizationError,
+    #[error("authorization error: {0}")]
+    AuthorizationError(String),
     #[error("api error")]
     ApiError(#[from] reqwest::Error),
     #[error("invalid file path")]
@@ -30,42 +31,42 @@ pub enum TranscriptionError {
 #[derive(Debug, Deserialize)]
 pub struct TranscriptionResponse {
     pub text: String,
+    // length of the audio in seconds, which is what transcription is billed by
+    #[serde(default)]
+    pub duration: f64,
 }
 
-const OPENAI_TRANSCRIPTION_URL: &str = "https://api.openai.com/v1/audio/transcriptions";
+const TRANSCRIPTION_MODEL: &str = "whisper-1";
 
-pub async fn transcribe_audio(audio_data: Vec<u8>) -> Result<String, TranscriptionError> {
-    let api_key = match env::var("OPENAI_API_KEY") {
-        Ok(key) => key,
-        Err(_) => return Err(TranscriptionError::AuthorizationError),
+pub async fn transcribe_audio(
+    audio_data: Vec<u8>,
+    purpose: Purpose,
+) -> Result<TranscriptionResponse, TranscriptionError> {
+    let (url, headers) = match openai_target(TRANSCRIPTION_MODEL, "audio/transcriptions", None) {
+        Ok(target) => target,
+        Err(e) => return Err(TranscriptionError::AuthorizationError(e.to_string())),
     };
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        match HeaderValue::from_str(&format!("Bearer {api_key}")) {
-            Ok(value) => value,
-            Err(_) => return Err(TranscriptionError::AuthorizationError),
-        },
-    );
-
-    let file_part = Part::bytes(audio_data)
-        .file_name("audio.wav")
-        .mime_str("audio/wav")
-        .map_err(|e| TranscriptionError::ApiError(e.into()))?;
-
-    let form = Form::new()
-        .part("file", file_part)
-        .text("model", "whisper-1")
-        .text("response_format", "json");
+    // the form is consumed when sent, so it is rebuilt for every attempt
+    let build_form = || -> Result<Form, reqwest::Error> {
+        let file_part = Part::bytes(audio_data.clone())
+            .file_name("audio.wav")
+            .mime_str("audio/wav")?;
+        Ok(Form::new()
+            .part("file", file_part)
+            .text("model", TRANSCRIPTION_MODEL)
+            .text("response_format", "verbose_json"))
+    };
+    build_form().map_err(TranscriptionError::ApiError)?;
 
-    let client = reqwest::Client::new();
-    let response = match client
-        .post(OPENAI_TRANSCRIPTION_URL)
-        .headers(headers)
-        .multipart(form)
-        .send()
-        .await
+    let response = match send_with_retries(|client| {
+        let request = client.post(&url).headers(headers.clone());
+        match build_form() {
+            Ok(form) => request.multipart(form),
+            Err(_) => request,
+        }
+    })
+    .await
     {
         Ok(response) => response,
         Err(e) => return Err(TranscriptionError::ApiError(e)),
@@ -76,12 +77,14 @@ pub async fn transcribe_audio(audio_data: Vec<u8>) -> Result<String, Transcripti
         Err(e) => return Err(TranscriptionError::ApiError(e)),
     };
 
-    Ok(response_body.text)
+    ledger::record_transcription(purpose, TRANSCRIPTION_MODEL, response_body.duration);
+    Ok(response_body)
 }
 
 pub async fn transcribe_audio_from_file_path<P: AsRef<Path>>(
     file_path: P,
-) -> Result<String, TranscriptionFromFilePathError> {
+    purpose: Purpose,
+) -> Result<TranscriptionResponse, TranscriptionFromFilePathError> {
     let mut file = match File::open(file_path) {
         Ok(file) => file,
         Err(e) => return Err(TranscriptionFromFilePathError::InvalidFilePath(e)),
@@ -90,8 +93,8 @@ pub async fn transcribe_audio_from_file_path<P: AsRef<Path>>(
     if let Err(e) = file.read_to_end(&mut buffer) {
         return Err(TranscriptionFromFilePathError::InvalidFilePath(e));
     }
-    match transcribe_audio(buffer).await {
-        Ok(text) => Ok(text),
+    match transcribe_audio(buffer, purpose).await {
+        Ok(response) => Ok(response),
         Err(e) => Err(TranscriptionFromFilePathError::TranscriptionError(e)),
     }
 }
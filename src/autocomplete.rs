@@ -1,11 +1,13 @@
+use crate::budget;
+use crate::ledger::Purpose;
 use crate::llm::{CompletionBuilder, LLMError, Message, MessageContent, Model, Provider, Role};
 use crate::prompts::AUTOCOMPLETE_SYSTEM_PROMPT;
 use crate::screenshot::take_screenshot;
 use crate::screenshot::ScreenshotError;
 use crate::trajectory::Trajectory;
-use crate::utils::{parse_markdown_code_block, MarkdownCodeBlockMissingError};
 use device_query::{DeviceQuery, DeviceState, Keycode};
 use enigo::{Enigo, InputError, Keyboard, Settings};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use thiserror::Error;
@@ -19,19 +21,9 @@ pub enum AutocompleteError {
     ScreenshotError(#[from] ScreenshotError),
     #[error("Error typing text")]
     TypingError(#[from] InputError),
-    #[error("Error parsing autocomplete response")]
-    ParseAutocompleteResponseError(#[from] ParseAutocompleteResponseError),
 }
 
-#[derive(Error, Debug)]
-pub enum ParseAutocompleteResponseError {
-    #[error("Markdown code block missing")]
-    MarkdownCodeBlockMissingError(#[from] MarkdownCodeBlockMissingError),
-    #[error("Error parsing JSON")]
-    ParseJsonError(#[from] serde_json::Error),
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct AutocompleteResponse {
     autocomplete: String,
 }
@@ -41,17 +33,20 @@ async fn handle_autocomplete(
 ) -> Result<String, AutocompleteError> {
     let screenshot = take_screenshot().await?;
     trajectory.lock().await.add_screenshot(screenshot).await;
-    let response = match generate_autocompletion(trajectory.clone()).await {
+    let autocomplete_response = match generate_autocompletion(trajectory.clone()).await {
         Ok(response) => response,
         Err(e) => {
             return Err(AutocompleteError::GenerateAutocompletionError(e));
         }
     };
-    println!("Autocompletion generated: {}", response);
-    let response_clone = response.clone();
+    println!(
+        "Autocompletion generated: {}",
+        autocomplete_response.autocomplete
+    );
+    let autocomplete = autocomplete_response.autocomplete.clone();
     match tokio::task::spawn_blocking(move || {
         let mut enigo = Enigo::new(&Settings::default()).unwrap();
-        enigo.text(&response_clone)
+        enigo.text(&autocomplete)
     })
     .await
     .unwrap()
@@ -61,12 +56,6 @@ async fn handle_autocomplete(
             return Err(AutocompleteError::TypingError(e));
         }
     }
-    let autocomplete_response = match parse_autocomplete_response(&response) {
-        Ok(autocomplete_response) => autocomplete_response,
-        Err(e) => {
-            return Err(AutocompleteError::ParseAutocompleteResponseError(e));
-        }
-    };
     trajectory
         .lock()
         .await
@@ -75,15 +64,28 @@ async fn handle_autocomplete(
     Ok(autocomplete_response.autocomplete)
 }
 
-async fn generate_autocompletion(trajectory: Arc<Mutex<Trajectory>>) -> Result<String, LLMError> {
+async fn generate_autocompletion(
+    trajectory: Arc<Mutex<Trajectory>>,
+) -> Result<AutocompleteResponse, LLMError> {
     let messages = trajectory.lock().await.build_messages(None).await.unwrap();
     let completion_request = CompletionBuilder::new()
         .model(Model::Claude35Sonnet)
         .provider(Provider::Anthropic)
         .messages(messages)
+        .purpose(Purpose::Autocomplete)
         .temperature(0.0)
         .build();
-    completion_request.do_request().await
+    let autocomplete_response: AutocompleteResponse =
+        completion_request.complete_structured().await?;
+    // only the first line is typed
+    let autocomplete = autocomplete_response
+        .autocomplete
+        .split('\n')
+        .next()
+        .unwrap_or("");
+    Ok(AutocompleteResponse {
+        autocomplete: autocomplete.to_string(),
+    })
 }
 
 pub async fn run_autocomplete() -> Result<(), Box<dyn std::error::Error>> {
@@ -100,6 +102,9 @@ pub async fn run_autocomplete() -> Result<(), Box<dyn std::error::Error>> {
                         .await
                         .add_screenshot(screenshot)
                         .await;
+                    for warning in budget::take_warnings() {
+                        println!("[warning] {}", warning);
+                    }
                 }
                 Err(e) => eprintln!("Screenshot error: {:?}", e),
             }
@@ -146,24 +151,3 @@ pub async fn run_autocomplete() -> Result<(), Box<dyn std::error::Error>> {
     screenshot_task_handle.abort();
     Ok(())
 }
-
-fn parse_autocomplete_response(
-    response: &str,
-) -> Result<AutocompleteResponse, ParseAutocompleteResponseError> {
-    let json_string = match parse_markdown_code_block(response) {
-        Ok(json_string) => json_string,
-        Err(e) => return Err(ParseAutocompleteResponseError::MarkdownCodeBlockMissingError(e)),
-    };
-    let autocomplete_response: AutocompleteResponse = match serde_json::from_str(&json_string) {
-        Ok(autocomplete_response) => autocomplete_response,
-        Err(e) => return Err(ParseAutocompleteResponseError::ParseJsonError(e)),
-    };
-    let autocomplete_response = autocomplete_response
-        .autocomplete
-        .split('\n')
-        .next()
-        .unwrap_or("");
-    Ok(AutocompleteResponse {
-        autocomplete: autocomplete_response.to_string(),
-    })
-}
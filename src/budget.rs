@@ -0,0 +1,143 @@
+use crate::ledger::{usage_since, Purpose};
+use chrono::{Duration, Utc};
+use std::sync::{Mutex, OnceLock};
+
+// the purposes of the calls made by the background screenshot workers
+const BACKGROUND_PURPOSES: [Purpose; 2] = [Purpose::Description, Purpose::RedundancyCheck];
+// share of a cap after which the LLM redundancy checks are skipped
+const SKIP_REDUNDANCY_CHECKS_THRESHOLD: f64 = 0.5;
+// share of a cap after which only every `DESCRIBE_EVERY_NTH_FRAME`th screenshot is described
+const THROTTLE_DESCRIPTIONS_THRESHOLD: f64 = 0.75;
+const DESCRIBE_EVERY_NTH_FRAME: usize = 6;
+
+// limits on what the background workers spend, `None` means unlimited; all are opt-in
+#[derive(Debug, Clone, Default)]
+pub struct SpendCaps {
+    pub hourly_spend_usd: Option<f64>,
+    pub daily_spend_usd: Option<f64>,
+    pub hourly_requests: Option<usize>,
+    pub daily_requests: Option<usize>,
+}
+
+// from the most to the least capable, as the spend approaches the caps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BackgroundMode {
+    Full,
+    SkipRedundancyChecks,
+    // describe only every nth screenshot
+    ThrottleDescriptions(usize),
+    Paused,
+}
+
+impl BackgroundMode {
+    pub fn redundancy_checks_enabled(&self) -> bool {
+        matches!(self, BackgroundMode::Full)
+    }
+
+    // `frame` counts the screenshots added so far
+    pub fn should_describe(&self, frame: usize) -> bool {
+        match self {
+            BackgroundMode::Full | BackgroundMode::SkipRedundancyChecks => true,
+            BackgroundMode::ThrottleDescriptions(n) => frame.is_multiple_of(*n),
+            BackgroundMode::Paused => false,
+        }
+    }
+
+    fn warning(&self) -> String {
+        match self {
+            BackgroundMode::Full => {
+                "Background spend is back under the caps, resuming screenshot analysis".to_string()
+            }
+            BackgroundMode::SkipRedundancyChecks => {
+                "Background spend is past half of a cap, skipping redundant screenshot checks"
+                    .to_string()
+            }
+            BackgroundMode::ThrottleDescriptions(n) => format!(
+                "Background spend is close to a cap, describing only every {}th screenshot",
+                n
+            ),
+            BackgroundMode::Paused => {
+                "Background spend cap reached, pausing screenshot descriptions".to_string()
+            }
+        }
+    }
+}
+
+static CAPS: OnceLock<SpendCaps> = OnceLock::new();
+static STATE: OnceLock<Mutex<BudgetState>> = OnceLock::new();
+
+#[derive(Debug)]
+struct BudgetState {
+    mode: BackgroundMode,
+    warnings: Vec<String>,
+}
+
+// must be called before the first screenshot, returns the caps back if it is too late
+pub fn configure(caps: SpendCaps) -> Result<(), SpendCaps> {
+    CAPS.set(caps)
+}
+
+fn caps() -> &'static SpendCaps {
+    CAPS.get_or_init(SpendCaps::default)
+}
+
+fn state() -> &'static Mutex<BudgetState> {
+    STATE.get_or_init(|| {
+        Mutex::new(BudgetState {
+            mode: BackgroundMode::Full,
+            warnings: Vec::new(),
+        })
+    })
+}
+
+// how much the background workers may do right now, given what they spent recently;
+// changes of mode are queued as warnings for `take_warnings`
+pub fn background_mode() -> BackgroundMode {
+    let caps = caps();
+    let now = Utc::now();
+    let (hourly_requests, hourly_spend) =
+        usage_since(&BACKGROUND_PURPOSES, now - Duration::hours(1));
+    let (daily_requests, daily_spend) = usage_since(&BACKGROUND_PURPOSES, now - Duration::days(1));
+    let usage = [
+        share(hourly_spend, caps.hourly_spend_usd),
+        share(daily_spend, caps.daily_spend_usd),
+        share(
+            hourly_requests as f64,
+            caps.hourly_requests.map(|cap| cap as f64),
+        ),
+        share(
+            daily_requests as f64,
+            caps.daily_requests.map(|cap| cap as f64),
+        ),
+    ]
+    .into_iter()
+    .fold(0.0, f64::max);
+    let mode = if usage >= 1.0 {
+        BackgroundMode::Paused
+    } else if usage >= THROTTLE_DESCRIPTIONS_THRESHOLD {
+        BackgroundMode::ThrottleDescriptions(DESCRIBE_EVERY_NTH_FRAME)
+    } else if usage >= SKIP_REDUNDANCY_CHECKS_THRESHOLD {
+        BackgroundMode::SkipRedundancyChecks
+    } else {
+        BackgroundMode::Full
+    };
+    let mut state = state().lock().unwrap_or_else(|e| e.into_inner());
+    if state.mode != mode {
+        state.mode = mode;
+        state.warnings.push(mode.warning());
+    }
+    mode
+}
+
+fn share(used: f64, cap: Option<f64>) -> f64 {
+    match cap {
+        Some(cap) if cap > 0.0 => used / cap,
+        Some(_) => 1.0,
+        None => 0.0,
+    }
+}
+
+// the mode changes since the last call, for the shell to show
+pub fn take_warnings() -> Vec<String> {
+    std::mem::take(&mut state().lock().unwrap_or_else(|e| e.into_inner()).warnings)
+}
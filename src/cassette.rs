@@ -0,0 +1,463 @@
+use crate::screenshot::ScreenshotFormat;
+use futures::stream::{self, StreamExt};
+use reqwest::header::HeaderMap;
+use reqwest::{RequestBuilder, Response, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    // send requests as usual and write every request/response pair and screenshot to the cassette
+    Record,
+    // serve the recorded responses and screenshots without touching the network or the screen
+    Replay,
+}
+
+#[derive(Debug, Clone)]
+pub struct CassetteConfig {
+    pub dir: PathBuf,
+    pub mode: CassetteMode,
+}
+
+// one file per request, named by the order the requests were sent in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+// request headers are not recorded since they carry the API keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+    body_hash: String,
+    // `None` for multipart bodies, which are streamed
+    body: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    // the body was dropped before it was read to the end, e.g. a cancelled stream, so only the
+    // part that had been received is recorded
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+}
+
+// kept in the `screenshots` directory of the cassette, since the requests embed them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RecordedScreenshot {
+    pub timestamp: SystemTime,
+    pub format: ScreenshotFormat,
+    // base64 encoded image data
+    pub image_data: String,
+}
+
+type ReplayState = HashMap<PathBuf, Vec<(Interaction, bool)>>;
+type ScreenshotReplayState = HashMap<PathBuf, VecDeque<RecordedScreenshot>>;
+
+// the next file number of each directory being recorded to
+static NEXT_INDEX: OnceLock<Mutex<HashMap<PathBuf, usize>>> = OnceLock::new();
+static REPLAY_STATE: OnceLock<Mutex<ReplayState>> = OnceLock::new();
+static SCREENSHOT_REPLAY_STATE: OnceLock<Mutex<ScreenshotReplayState>> = OnceLock::new();
+
+pub(crate) async fn send(
+    config: &CassetteConfig,
+    client: &reqwest::Client,
+    request: RequestBuilder,
+) -> Result<Response, reqwest::Error> {
+    let request = request.build()?;
+    let body = request.body().and_then(|body| body.as_bytes());
+    let recorded_request = RecordedRequest {
+        method: request.method().to_string(),
+        url: redact_url(request.url()),
+        body_hash: format!("{:x}", Sha256::digest(body.unwrap_or_default())),
+        body: body.map(|bytes| {
+            serde_json::from_slice(bytes).unwrap_or_else(|_| {
+                serde_json::Value::String(String::from_utf8_lossy(bytes).into_owned())
+            })
+        }),
+    };
+    match config.mode {
+        CassetteMode::Replay => Ok(replay(&config.dir, &recorded_request)),
+        CassetteMode::Record => {
+            let path = config
+                .dir
+                .join(format!("{:06}.json", next_index(&config.dir)));
+            let response = client.execute(request).await?;
+            Ok(record(path, recorded_request, response))
+        }
+    }
+}
+
+// some gateways take the API key in the query string
+fn redact_url(url: &Url) -> String {
+    let mut url = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| name != "key")
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+// numbers the files after the ones already in `dir`, so recording to an existing cassette adds
+// to it rather than overwriting it
+fn next_index(dir: &Path) -> usize {
+    let mut next_indexes = NEXT_INDEX
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let next_index = next_indexes
+        .entry(dir.to_path_buf())
+        .or_insert_with(|| first_free_index(dir));
+    let index = *next_index;
+    *next_index += 1;
+    index
+}
+
+fn first_free_index(dir: &Path) -> usize {
+    json_files(dir)
+        .iter()
+        .filter_map(|path| path.file_stem()?.to_str()?.parse::<usize>().ok())
+        .max()
+        .map_or(0, |index| index + 1)
+}
+
+// writes the interaction once the body has been read to the end, or with the part received so far
+// when the body is dropped before that
+struct Recorder {
+    path: PathBuf,
+    request: Option<RecordedRequest>,
+    status: u16,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Recorder {
+    fn finish(&mut self, truncated: bool) {
+        if let Some(request) = self.request.take() {
+            write_interaction(
+                &self.path,
+                request,
+                self.status,
+                &self.headers,
+                &self.body,
+                truncated,
+            );
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.finish(true);
+    }
+}
+
+// passes the body through as it arrives, so streaming still works while recording
+fn record(path: PathBuf, request: RecordedRequest, response: Response) -> Response {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let recorder = Recorder {
+        path,
+        request: Some(request),
+        status: status.as_u16(),
+        headers: headers.clone(),
+        body: Vec::new(),
+    };
+    let state = (response.bytes_stream().boxed(), recorder);
+    let body = stream::unfold(state, |(mut bytes, mut recorder)| async move {
+        match bytes.next().await {
+            Some(Ok(chunk)) => {
+                recorder.body.extend_from_slice(&chunk);
+                Some((Ok(chunk), (bytes, recorder)))
+            }
+            Some(Err(e)) => Some((Err(e), (bytes, recorder))),
+            None => {
+                recorder.finish(false);
+                None
+            }
+        }
+    });
+    let mut builder = http::Response::builder().status(status);
+    for (name, value) in recordable_headers(&headers) {
+        builder = builder.header(name, value);
+    }
+    match builder.body(reqwest::Body::wrap_stream(body)) {
+        Ok(response) => Response::from(response),
+        Err(e) => error_response(&format!("Error recording response: {}", e)),
+    }
+}
+
+fn recordable_headers(
+    headers: &HeaderMap,
+) -> impl Iterator<Item = (&reqwest::header::HeaderName, &reqwest::header::HeaderValue)> {
+    headers
+        .iter()
+        .filter(|(name, _)| *name != reqwest::header::SET_COOKIE)
+}
+
+fn write_interaction(
+    path: &Path,
+    request: RecordedRequest,
+    status: u16,
+    headers: &HeaderMap,
+    body: &[u8],
+    truncated: bool,
+) {
+    let interaction = Interaction {
+        request,
+        response: RecordedResponse {
+            status,
+            headers: recordable_headers(headers)
+                .filter_map(|(name, value)| {
+                    value
+                        .to_str()
+                        .ok()
+                        .map(|value| (name.to_string(), value.to_string()))
+                })
+                .collect(),
+            body: String::from_utf8_lossy(body).into_owned(),
+            truncated,
+        },
+    };
+    write_json(path, &interaction);
+}
+
+fn write_json(path: &Path, value: &impl Serialize) {
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    match serde_json::to_vec_pretty(value) {
+        Ok(bytes) => {
+            if let Err(e) = std::fs::write(path, bytes) {
+                println!("[warning] Error writing cassette {}: {}", path.display(), e);
+            }
+        }
+        Err(e) => println!("[warning] Error serializing cassette: {}", e),
+    }
+}
+
+fn json_files(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension().and_then(|extension| extension.to_str()) == Some("json")
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+fn load_json_files<T: DeserializeOwned>(dir: &Path) -> Vec<T> {
+    json_files(dir)
+        .into_iter()
+        .filter_map(|path| std::fs::read(path).ok())
+        .filter_map(|bytes| serde_json::from_slice(&bytes).ok())
+        .collect()
+}
+
+// serves the first unused recording of the exact same request; the screenshots are replayed too,
+// so a replayed session sends the same requests as the recorded one
+fn replay(dir: &Path, request: &RecordedRequest) -> Response {
+    let state = REPLAY_STATE.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    let interactions = state.entry(dir.to_path_buf()).or_insert_with(|| {
+        load_json_files(dir)
+            .into_iter()
+            .map(|interaction| (interaction, false))
+            .collect()
+    });
+    let position = interactions.iter().position(|(interaction, used)| {
+        !used
+            && interaction.request.method == request.method
+            && interaction.request.url == request.url
+            && interaction.request.body_hash == request.body_hash
+    });
+    let position = match position {
+        Some(position) => position,
+        None => {
+            return error_response(&format!(
+                "No recorded response left for {} {} with body hash {}",
+                request.method, request.url, request.body_hash
+            ))
+        }
+    };
+    interactions[position].1 = true;
+    let recorded = &interactions[position].0.response;
+    let mut builder = http::Response::builder().status(recorded.status);
+    for (name, value) in &recorded.headers {
+        builder = builder.header(name, value);
+    }
+    match builder.body(reqwest::Body::from(recorded.body.clone())) {
+        Ok(response) => Response::from(response),
+        Err(e) => error_response(&format!("Error replaying response: {}", e)),
+    }
+}
+
+pub(crate) fn record_screenshot(config: &CassetteConfig, screenshot: &RecordedScreenshot) {
+    let dir = config.dir.join("screenshots");
+    let path = dir.join(format!("{:06}.json", next_index(&dir)));
+    write_json(&path, screenshot);
+}
+
+// the recorded screenshots in the order they were taken, `None` once they have all been replayed
+pub(crate) fn replay_screenshot(config: &CassetteConfig) -> Option<RecordedScreenshot> {
+    let dir = config.dir.join("screenshots");
+    let state = SCREENSHOT_REPLAY_STATE.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    state
+        .entry(dir.clone())
+        .or_insert_with(|| load_json_files(&dir).into())
+        .pop_front()
+}
+
+// a 404 is not retried and is reported by the callers like any other API error
+fn error_response(message: &str) -> Response {
+    let response = http::Response::builder()
+        .status(404)
+        .body(reqwest::Body::from(message.to_string()))
+        .unwrap_or_else(|_| http::Response::new(reqwest::Body::from(message.to_string())));
+    Response::from(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn cassette_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("captain-cassette-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // answers every request with the same JSON body and counts the requests
+    async fn serve(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/messages", listener.local_addr().unwrap());
+        let num_requests = Arc::new(AtomicUsize::new(0));
+        let counter = num_requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buffer = vec![0; 64 * 1024];
+                let _ = socket.read(&mut buffer).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, num_requests)
+    }
+
+    fn config(dir: &Path, mode: CassetteMode) -> CassetteConfig {
+        CassetteConfig {
+            dir: dir.to_path_buf(),
+            mode,
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_the_recorded_response_without_the_network() {
+        let dir = cassette_dir("replay");
+        let (url, num_requests) = serve(r#"{"content": "recorded"}"#).await;
+        let client = reqwest::Client::new();
+        let request = |prompt: &str| {
+            client
+                .post(&url)
+                .body(format!(r#"{{"prompt": "{prompt}"}}"#))
+        };
+
+        let recorded = send(&config(&dir, CassetteMode::Record), &client, request("hi"))
+            .await
+            .unwrap();
+        assert_eq!(recorded.text().await.unwrap(), r#"{"content": "recorded"}"#);
+        assert_eq!(num_requests.load(Ordering::SeqCst), 1);
+
+        let replay_config = config(&dir, CassetteMode::Replay);
+        let replayed = send(&replay_config, &client, request("hi")).await.unwrap();
+        assert_eq!(replayed.status(), 200);
+        assert_eq!(replayed.text().await.unwrap(), r#"{"content": "recorded"}"#);
+        // a different request, or the same one again, is not served another recording
+        let different = send(&replay_config, &client, request("hello"))
+            .await
+            .unwrap();
+        assert_eq!(different.status(), 404);
+        let again = send(&replay_config, &client, request("hi")).await.unwrap();
+        assert_eq!(again.status(), 404);
+        assert_eq!(num_requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn records_after_the_existing_files_and_keeps_dropped_bodies() {
+        let dir = cassette_dir("record");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("000004.json"), "{}").unwrap();
+        let (url, _) = serve(r#"{"content": "dropped"}"#).await;
+        let client = reqwest::Client::new();
+
+        let response = send(
+            &config(&dir, CassetteMode::Record),
+            &client,
+            client.post(&url).body("{}"),
+        )
+        .await
+        .unwrap();
+        drop(response);
+
+        assert_eq!(
+            std::fs::read_to_string(dir.join("000004.json")).unwrap(),
+            "{}"
+        );
+        let interaction: Interaction =
+            serde_json::from_slice(&std::fs::read(dir.join("000005.json")).unwrap()).unwrap();
+        assert!(interaction.response.truncated);
+        assert_eq!(interaction.response.status, 200);
+    }
+
+    #[test]
+    fn replays_the_recorded_screenshots_in_order() {
+        let dir = cassette_dir("screenshots");
+        let record_config = config(&dir, CassetteMode::Record);
+        for image_data in ["first", "second"] {
+            record_screenshot(
+                &record_config,
+                &RecordedScreenshot {
+                    timestamp: SystemTime::UNIX_EPOCH,
+                    format: ScreenshotFormat::Png,
+                    image_data: image_data.to_string(),
+                },
+            );
+        }
+        let replay_config = config(&dir, CassetteMode::Replay);
+        let replayed: Vec<String> = std::iter::from_fn(|| replay_screenshot(&replay_config))
+            .map(|screenshot| screenshot.image_data)
+            .collect();
+        assert_eq!(replayed, ["first", "second"]);
+    }
+}
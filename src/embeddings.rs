@@ -1,8 +1,13 @@
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use crate::http_client::send_with_retries;
+use crate::ledger::{self, Purpose};
+use crate::llm::endpoint::openai_target;
+use crate::llm::scheduler::{self, Priority};
+use crate::llm::{LLMError, Usage};
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
-use std::env;
+use thiserror::Error;
 
-const OPENAI_API_URL: &str = "https://api.openai.com/v1/embeddings";
+const EMBEDDING_MODEL: &str = "text-embedding-3-small";
 
 #[derive(Serialize)]
 struct RequestBody {
@@ -13,6 +18,18 @@ struct RequestBody {
 #[derive(Deserialize)]
 struct Response {
     data: Vec<EmbeddingData>,
+    usage: ResponseUsage,
+}
+
+#[derive(Deserialize)]
+struct ResponseUsage {
+    prompt_tokens: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Embeddings {
+    pub embeddings: Vec<Vec<f32>>,
+    pub usage: Usage,
 }
 
 #[derive(Deserialize)]
@@ -20,44 +37,56 @@ struct EmbeddingData {
     embedding: Vec<f32>,
 }
 
-pub async fn embedding(texts: Vec<String>) -> Result<Vec<Vec<f32>>, reqwest::Error> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set");
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {api_key}")).unwrap(),
-    );
+#[derive(Error, Debug)]
+pub enum EmbeddingError {
+    #[error("Error building embedding request: {0}")]
+    RequestBuildingError(#[from] LLMError),
+    #[error("Error sending embedding request: {0}")]
+    ApiError(#[from] reqwest::Error),
+}
+
+pub async fn embedding(texts: Vec<String>, purpose: Purpose) -> Result<Embeddings, EmbeddingError> {
+    let (url, mut headers) = match openai_target(EMBEDDING_MODEL, "embeddings", None) {
+        Ok(target) => target,
+        Err(e) => return Err(EmbeddingError::RequestBuildingError(e)),
+    };
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
 
     let req_body = RequestBody {
-        model: "text-embedding-3-small",
+        model: EMBEDDING_MODEL,
         input: texts,
     };
 
-    let client = reqwest::Client::new();
-    let response = match client
-        .post(OPENAI_API_URL)
-        .headers(headers)
-        .json(&req_body)
-        .send()
-        .await
+    let _permit = scheduler::acquire("openai", Priority::for_purpose(purpose), None).await;
+    let response = match send_with_retries(|client| {
+        client.post(&url).headers(headers.clone()).json(&req_body)
+    })
+    .await
     {
         Ok(response) => response,
         Err(e) => {
             println!("Error: {}", e);
-            return Err(e);
+            return Err(EmbeddingError::ApiError(e));
         }
     };
     let response_body: Response = match response.json().await {
         Ok(response_body) => response_body,
         Err(e) => {
             println!("Error: {}", e);
-            return Err(e);
+            return Err(EmbeddingError::ApiError(e));
         }
     };
-    Ok(response_body
-        .data
-        .into_iter()
-        .map(|d| d.embedding)
-        .collect())
+    ledger::record_embedding(purpose, EMBEDDING_MODEL, response_body.usage.prompt_tokens);
+    Ok(Embeddings {
+        embeddings: response_body
+            .data
+            .into_iter()
+            .map(|d| d.embedding)
+            .collect(),
+        usage: Usage {
+            input_tokens: response_body.usage.prompt_tokens,
+            output_tokens: 0,
+            ..Default::default()
+        },
+    })
 }
@@ -0,0 +1,161 @@
+use crate::cassette::{self, CassetteConfig, CassetteMode};
+use crate::llm::scheduler;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    // maximum time between two reads of the response, so long streams are not cut off
+    pub read_timeout: Duration,
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // upper bound on how long a `Retry-After` (or rate limit reset) header can make us wait
+    pub max_retry_after: Duration,
+    // records all traffic to, or replays it from, a cassette directory
+    pub cassette: Option<CassetteConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(120),
+            max_retries: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_retry_after: Duration::from_secs(120),
+            cassette: None,
+        }
+    }
+}
+
+static CONFIG: OnceLock<HttpConfig> = OnceLock::new();
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+// must be called before the first request, returns the config back if it is too late
+pub fn configure(config: HttpConfig) -> Result<(), HttpConfig> {
+    CONFIG.set(config)
+}
+
+fn config() -> &'static HttpConfig {
+    CONFIG.get_or_init(HttpConfig::default)
+}
+
+// the cassette the traffic is recorded to or replayed from, if any
+pub fn cassette() -> Option<&'static CassetteConfig> {
+    config().cassette.as_ref()
+}
+
+pub fn is_replaying() -> bool {
+    cassette().is_some_and(|cassette_config| cassette_config.mode == CassetteMode::Replay)
+}
+
+// one pooled client for the whole process
+pub fn client() -> &'static reqwest::Client {
+    CLIENT.get_or_init(|| {
+        let config = config();
+        reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .read_timeout(config.read_timeout)
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new())
+    })
+}
+
+// sends the request built by `build_request`, rebuilding and resending it on connection errors,
+// timeouts, 429s and 5xxs; after the last retry the final response is returned as is so the
+// caller can report the error
+pub async fn send_with_retries<F>(build_request: F) -> Result<Response, reqwest::Error>
+where
+    F: Fn(&reqwest::Client) -> RequestBuilder,
+{
+    let config = config();
+    let mut attempt = 0;
+    loop {
+        let result = match &config.cassette {
+            Some(cassette_config) => {
+                cassette::send(cassette_config, client(), build_request(client())).await
+            }
+            None => build_request(client()).send().await,
+        };
+        let retry_after = match &result {
+            Ok(response) if is_retryable_status(response.status()) => {
+                retry_after_from_headers(response.headers())
+            }
+            Ok(_) => return result,
+            Err(e) if e.is_connect() || e.is_timeout() => None,
+            Err(_) => return result,
+        };
+        if attempt >= config.max_retries {
+            return result;
+        }
+        let delay = match retry_after {
+            Some(retry_after) => retry_after.min(config.max_retry_after) + jitter(config),
+            None => backoff(config, attempt),
+        };
+        // replayed retries do not need to wait for anything; the scheduler slot of the request is
+        // free for the others while it waits
+        if !is_replaying() {
+            scheduler::release_while(tokio::time::sleep(delay)).await;
+        }
+        attempt += 1;
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    // 529 is Anthropic's "overloaded"
+    matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+// exponential backoff with full jitter
+fn backoff(config: &HttpConfig, attempt: u32) -> Duration {
+    let exponential = config
+        .initial_backoff
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(config.max_backoff);
+    let millis = exponential.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+}
+
+fn jitter(config: &HttpConfig) -> Duration {
+    let millis = config.initial_backoff.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+}
+
+pub(crate) fn retry_after_from_headers(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(millis) = header_str(headers, "retry-after-ms").and_then(|v| v.parse().ok()) {
+        return Some(Duration::from_millis(millis));
+    }
+    if let Some(value) = header_str(headers, "retry-after") {
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+            return Some(duration_until(date.with_timezone(&Utc)));
+        }
+    }
+    // Anthropic reports when each exhausted limit resets as an RFC 3339 timestamp
+    ["requests", "tokens", "input-tokens", "output-tokens"]
+        .iter()
+        .filter(|limit| {
+            header_str(headers, &format!("anthropic-ratelimit-{limit}-remaining")) == Some("0")
+        })
+        .filter_map(|limit| header_str(headers, &format!("anthropic-ratelimit-{limit}-reset")))
+        .filter_map(|value| DateTime::parse_from_rfc3339(value).ok())
+        .map(|date| duration_until(date.with_timezone(&Utc)))
+        .max()
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn duration_until(date: DateTime<Utc>) -> Duration {
+    (date - Utc::now()).to_std().unwrap_or(Duration::ZERO)
+}
@@ -1,8 +1,9 @@
+use crate::ledger::Purpose;
 use crate::llm::{CompletionBuilder, LLMError, Model, Provider};
 use crate::llm::{Message, MessageContent, Role};
 use crate::prompts::DISCARD_REDUNDANT_SCREENSHOT_SYSTEM_PROMPT;
 use crate::screenshot::Screenshot;
-use crate::utils::{parse_markdown_code_block, MarkdownCodeBlockMissingError};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -10,10 +11,6 @@ use thiserror::Error;
 pub enum DiscardRedundantScreenshotError {
     #[error("Error generating text description of screenshot")]
     LLMError(#[from] LLMError),
-    #[error("Markdown code block missing in response")]
-    MarkdownCodeBlockMissingError(#[from] MarkdownCodeBlockMissingError),
-    #[error("Error parsing JSON response")]
-    JSONError(String),
 }
 
 const SIMILARITY_THRESHOLD_NUM_PIXELS: i32 = 1_000_000;
@@ -21,24 +18,36 @@ const SIMILARITY_THRESHOLD_NUM_PIXELS: i32 = 1_000_000;
 pub async fn is_redundant_screenshot(
     last_screenshot: &Screenshot,
     current_screenshot: &Screenshot,
+    provider: Provider,
+    model: Model,
 ) -> Result<bool, DiscardRedundantScreenshotError> {
     if last_screenshot.image == current_screenshot.image {
         return Ok(true);
     }
     if detect_temporal_change_in_same_content(last_screenshot, current_screenshot) {
-        return should_discard_past_screenshot(last_screenshot, current_screenshot).await;
+        return should_discard_past_screenshot(
+            last_screenshot,
+            current_screenshot,
+            provider,
+            model,
+        )
+        .await;
     }
     Ok(false)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct PreviousScreenshotContainsImportantInformationNotPresentInCurrentScreenshotResponse {
+    // comes first so that the model reasons before it answers
+    reasoning: String,
     previous_screenshot_contains_important_information_not_present_in_current_screenshot: bool,
 }
 
 async fn should_discard_past_screenshot(
     last_screenshot: &Screenshot,
     current_screenshot: &Screenshot,
+    provider: Provider,
+    model: Model,
 ) -> Result<bool, DiscardRedundantScreenshotError> {
     let messages = vec![
         Message {
@@ -55,27 +64,17 @@ async fn should_discard_past_screenshot(
         },
     ];
     let completion_request = CompletionBuilder::new()
-        .model(Model::GPT4oMini)
-        .provider(Provider::OpenAI)
+        .model(model)
+        .provider(provider)
         .messages(messages)
+        .purpose(Purpose::RedundancyCheck)
+        .cache(true)
+        .temperature(0.0)
         .build();
-    let response = match completion_request.do_request().await {
-        Ok(completion) => completion,
-        Err(e) => return Err(DiscardRedundantScreenshotError::LLMError(e)),
-    };
-    let json_string = match parse_markdown_code_block(&response) {
-        Ok(json_string) => json_string,
-        Err(e) => return Err(DiscardRedundantScreenshotError::MarkdownCodeBlockMissingError(e)),
-    };
     let json: PreviousScreenshotContainsImportantInformationNotPresentInCurrentScreenshotResponse =
-        match serde_json::from_str(&json_string) {
+        match completion_request.complete_structured().await {
             Ok(json) => json,
-            Err(e) => {
-                return Err(DiscardRedundantScreenshotError::JSONError(format!(
-                    "Error parsing JSON response: {}",
-                    e
-                )))
-            }
+            Err(e) => return Err(DiscardRedundantScreenshotError::LLMError(e)),
         };
     Ok(!json.previous_screenshot_contains_important_information_not_present_in_current_screenshot)
 }
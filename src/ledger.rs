@@ -0,0 +1,306 @@
+use crate::llm::pricing::{completion_cost, embedding_cost, transcription_cost};
+use crate::llm::{Model, Provider, Usage};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use thiserror::Error;
+
+// what an API call was made for, so the session cost can be broken down
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Purpose {
+    #[serde(rename = "description")]
+    Description,
+    #[serde(rename = "redundancy_check")]
+    RedundancyCheck,
+    #[serde(rename = "chat")]
+    Chat,
+    #[serde(rename = "autocomplete")]
+    Autocomplete,
+    #[default]
+    #[serde(rename = "other")]
+    Other,
+}
+
+impl Purpose {
+    const ALL: [Purpose; 5] = [
+        Purpose::Description,
+        Purpose::RedundancyCheck,
+        Purpose::Chat,
+        Purpose::Autocomplete,
+        Purpose::Other,
+    ];
+}
+
+impl fmt::Display for Purpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Purpose::Description => write!(f, "description"),
+            Purpose::RedundancyCheck => write!(f, "redundancy_check"),
+            Purpose::Chat => write!(f, "chat"),
+            Purpose::Autocomplete => write!(f, "autocomplete"),
+            Purpose::Other => write!(f, "other"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    // RFC 3339
+    pub timestamp: String,
+    pub purpose: Purpose,
+    pub provider: String,
+    pub model: String,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    // prompt cache writes and reads, which are not included in `input_tokens`
+    #[serde(default)]
+    pub cache_write_tokens: u32,
+    #[serde(default)]
+    pub cache_read_tokens: u32,
+    pub audio_seconds: f64,
+    // `None` when the model's price is unknown
+    pub cost_usd: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PurposeTotal {
+    pub purpose: Purpose,
+    pub calls: usize,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_write_tokens: u64,
+    pub cache_read_tokens: u64,
+    pub audio_seconds: f64,
+    pub cost_usd: f64,
+    // calls whose cost is not included in `cost_usd`
+    pub unpriced_calls: usize,
+}
+
+#[derive(Error, Debug)]
+pub enum LedgerExportError {
+    #[error("Error writing usage report: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Error serializing usage report: {0}")]
+    SerializationError(#[from] serde_json::Error),
+}
+
+static LEDGER: OnceLock<Mutex<Vec<LedgerEntry>>> = OnceLock::new();
+
+fn ledger() -> &'static Mutex<Vec<LedgerEntry>> {
+    LEDGER.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+fn record(entry: LedgerEntry) {
+    ledger()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(entry);
+}
+
+pub fn record_completion(
+    purpose: Purpose,
+    provider: &Provider,
+    model: &Model,
+    custom_model: Option<&str>,
+    usage: Usage,
+) {
+    let model_name = match (model, custom_model) {
+        (Model::Custom, Some(custom_model)) => custom_model.to_string(),
+        _ => model.to_string(),
+    };
+    record(LedgerEntry {
+        timestamp: Utc::now().to_rfc3339(),
+        purpose,
+        provider: provider.to_string(),
+        model: model_name,
+        input_tokens: usage.input_tokens,
+        output_tokens: usage.output_tokens,
+        cache_write_tokens: usage.cache_creation_input_tokens,
+        cache_read_tokens: usage.cache_read_input_tokens,
+        audio_seconds: 0.0,
+        cost_usd: completion_cost(provider, model, usage),
+    });
+}
+
+pub fn record_embedding(purpose: Purpose, model: &str, input_tokens: u32) {
+    record(LedgerEntry {
+        timestamp: Utc::now().to_rfc3339(),
+        purpose,
+        provider: Provider::OpenAI.to_string(),
+        model: model.to_string(),
+        input_tokens,
+        output_tokens: 0,
+        cache_write_tokens: 0,
+        cache_read_tokens: 0,
+        audio_seconds: 0.0,
+        cost_usd: Some(embedding_cost(input_tokens)),
+    });
+}
+
+pub fn record_transcription(purpose: Purpose, model: &str, audio_seconds: f64) {
+    record(LedgerEntry {
+        timestamp: Utc::now().to_rfc3339(),
+        purpose,
+        provider: Provider::OpenAI.to_string(),
+        model: model.to_string(),
+        input_tokens: 0,
+        output_tokens: 0,
+        cache_write_tokens: 0,
+        cache_read_tokens: 0,
+        audio_seconds,
+        cost_usd: Some(transcription_cost(audio_seconds)),
+    });
+}
+
+pub fn entries() -> Vec<LedgerEntry> {
+    ledger().lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// one total per purpose that has at least one call
+pub fn totals_by_purpose() -> Vec<PurposeTotal> {
+    let entries = entries();
+    Purpose::ALL
+        .iter()
+        .map(|&purpose| {
+            let mut total = PurposeTotal {
+                purpose,
+                ..Default::default()
+            };
+            for entry in entries.iter().filter(|entry| entry.purpose == purpose) {
+                total.calls += 1;
+                total.input_tokens += entry.input_tokens as u64;
+                total.output_tokens += entry.output_tokens as u64;
+                total.cache_write_tokens += entry.cache_write_tokens as u64;
+                total.cache_read_tokens += entry.cache_read_tokens as u64;
+                total.audio_seconds += entry.audio_seconds;
+                match entry.cost_usd {
+                    Some(cost) => total.cost_usd += cost,
+                    None => total.unpriced_calls += 1,
+                }
+            }
+            total
+        })
+        .filter(|total| total.calls > 0)
+        .collect()
+}
+
+pub fn summary() -> String {
+    let totals = totals_by_purpose();
+    if totals.is_empty() {
+        return "No API usage this session.".to_string();
+    }
+    let mut lines = vec![format!(
+        "{:<18} {:>6} {:>12} {:>12} {:>10}",
+        "purpose", "calls", "input", "output", "cost"
+    )];
+    let mut total_cost = 0.0;
+    let mut unpriced_calls = 0;
+    let mut cache_write_tokens = 0;
+    let mut cache_read_tokens = 0;
+    for total in &totals {
+        lines.push(format!(
+            "{:<18} {:>6} {:>12} {:>12} {:>10}",
+            total.purpose.to_string(),
+            total.calls,
+            total.input_tokens,
+            total.output_tokens,
+            format!("${:.4}", total.cost_usd)
+        ));
+        total_cost += total.cost_usd;
+        unpriced_calls += total.unpriced_calls;
+        cache_write_tokens += total.cache_write_tokens;
+        cache_read_tokens += total.cache_read_tokens;
+    }
+    lines.push(format!(
+        "{:<18} {:>43}",
+        "total",
+        format!("${:.4}", total_cost)
+    ));
+    if cache_write_tokens > 0 || cache_read_tokens > 0 {
+        lines.push(format!(
+            "(prompt cache: {} tokens written, {} tokens read, not included in input)",
+            cache_write_tokens, cache_read_tokens
+        ));
+    }
+    if unpriced_calls > 0 {
+        lines.push(format!(
+            "({} calls to models without a known price are not included)",
+            unpriced_calls
+        ));
+    }
+    lines.join("\n")
+}
+
+pub fn to_csv() -> String {
+    let mut csv = String::from(
+        "timestamp,purpose,provider,model,input_tokens,output_tokens,cache_write_tokens,cache_read_tokens,audio_seconds,cost_usd\n",
+    );
+    for entry in entries() {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{}\n",
+            entry.timestamp,
+            entry.purpose,
+            csv_field(&entry.provider),
+            csv_field(&entry.model),
+            entry.input_tokens,
+            entry.output_tokens,
+            entry.cache_write_tokens,
+            entry.cache_read_tokens,
+            entry.audio_seconds,
+            entry
+                .cost_usd
+                .map(|cost| cost.to_string())
+                .unwrap_or_default()
+        ));
+    }
+    csv
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn to_json() -> Result<String, serde_json::Error> {
+    #[derive(Serialize)]
+    struct Report {
+        totals: Vec<PurposeTotal>,
+        entries: Vec<LedgerEntry>,
+    }
+    serde_json::to_string_pretty(&Report {
+        totals: totals_by_purpose(),
+        entries: entries(),
+    })
+}
+
+// writes JSON for a `.json` path and CSV otherwise
+pub fn export(path: &Path) -> Result<(), LedgerExportError> {
+    let contents = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => to_json()?,
+        _ => to_csv(),
+    };
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+// number of calls and their known cost for the given purposes since `since`
+pub fn usage_since(purposes: &[Purpose], since: DateTime<Utc>) -> (usize, f64) {
+    ledger()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|entry| purposes.contains(&entry.purpose))
+        .filter(|entry| {
+            DateTime::parse_from_rfc3339(&entry.timestamp)
+                .map(|timestamp| timestamp.with_timezone(&Utc) >= since)
+                .unwrap_or(false)
+        })
+        .fold((0, 0.0), |(calls, cost), entry| {
+            (calls + 1, cost + entry.cost_usd.unwrap_or(0.0))
+        })
+}
@@ -1,25 +1,127 @@
-use crate::llm::{CompletionOptions, ContentBlock, LLMError, Message, MessageContent, Model};
+use crate::http_client::send_with_retries;
+use crate::llm::catalog::model_info;
+use crate::llm::endpoint::endpoint_config;
+use crate::llm::errors;
+use crate::llm::provider::{LlmProvider, ProviderCapabilities, SystemMessages};
+use crate::llm::streaming::{completion_stream, parse_json_event, sse_events};
+use crate::llm::{
+    Completion, CompletionChunk, CompletionOptions, CompletionStream, ContentBlock, LLMError,
+    Message, MessageContent, Model, Provider, ProviderError, Tool, Usage,
+};
+use async_trait::async_trait;
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
-use std::env;
 
-const ANTHROPIC_API_URL: &str = "https://api.anthropic.com/v1/messages";
+const ANTHROPIC_API_BASE_URL: &str = "https://api.anthropic.com/v1";
 const DEFAULT_ANTHROPIC_MAX_COMPLETION_TOKENS: i32 = 8192;
 
+// the response content blocks have the same shape as `ContentBlock`
 #[derive(Deserialize, Debug)]
 struct AnthropicResponse {
-    content: Vec<AnthropicContent>,
+    content: Vec<ContentBlock>,
+    usage: AnthropicUsage,
 }
 
 #[derive(Deserialize, Debug)]
-struct AnthropicContent {
-    text: String,
+struct AnthropicUsage {
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+    #[serde(default)]
+    cache_creation_input_tokens: u32,
+    #[serde(default)]
+    cache_read_input_tokens: u32,
+}
+
+impl From<AnthropicUsage> for Usage {
+    fn from(usage: AnthropicUsage) -> Self {
+        Usage {
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+            cache_creation_input_tokens: usage.cache_creation_input_tokens,
+            cache_read_input_tokens: usage.cache_read_input_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+enum AnthropicStreamEvent {
+    #[serde(rename = "message_start")]
+    MessageStart { message: AnthropicStreamMessage },
+    #[serde(rename = "content_block_delta")]
+    ContentBlockDelta { delta: AnthropicDelta },
+    #[serde(rename = "message_delta")]
+    MessageDelta { usage: AnthropicOutputUsage },
+    #[serde(rename = "error")]
+    Error { error: AnthropicStreamError },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicStreamMessage {
+    usage: AnthropicUsage,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicOutputUsage {
+    output_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+enum AnthropicDelta {
+    #[serde(rename = "text_delta")]
+    TextDelta { text: String },
+    #[serde(rename = "input_json_delta")]
+    InputJsonDelta { partial_json: String },
+    #[serde(rename = "thinking_delta")]
+    ThinkingDelta { thinking: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicStreamError {
+    #[serde(rename = "type")]
+    type_: String,
+    message: String,
+}
+
+// errors sent in the middle of a stream have no HTTP status of their own, so they get the one
+// the same error type comes with when it fails the request
+fn stream_error_status(error_type: &str) -> u16 {
+    match error_type {
+        "invalid_request_error" => 400,
+        "authentication_error" => 401,
+        "permission_error" => 403,
+        "not_found_error" => 404,
+        "request_too_large" => 413,
+        "rate_limit_error" => 429,
+        "overloaded_error" => 529,
+        _ => 500,
+    }
 }
 
 #[derive(Serialize, Debug)]
 struct AnthropicMessage<'a> {
     role: &'a str,
-    content: &'a [ContentBlock],
+    content: Vec<AnthropicContentBlock<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicContentBlock<'a> {
+    #[serde(flatten)]
+    block: &'a ContentBlock,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_control: Option<AnthropicCacheControl>,
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicCacheControl {
+    #[serde(rename = "type")]
+    type_: &'static str,
 }
 
 #[derive(Serialize)]
@@ -32,13 +134,260 @@ struct AnthropicRequest<'a> {
     max_tokens: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<AnthropicToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<AnthropicThinking>,
+}
+
+#[derive(Serialize)]
+struct AnthropicThinking {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    budget_tokens: u32,
+}
+
+// the API rejects stop sequences that are only whitespace, such as "\n", so those are applied to
+// the text as it arrives instead
+#[derive(Default)]
+struct LocalStop {
+    sequences: Vec<String>,
+    text: String,
+    emitted: usize,
+    stopped: bool,
+}
+
+impl LocalStop {
+    fn new(options: Option<&CompletionOptions>) -> Self {
+        Self {
+            sequences: options
+                .map(|opt| local_stop_sequences(&opt.stop))
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    // the part of the text so far that is known to come before any stop sequence
+    fn push(&mut self, delta: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        if self.sequences.is_empty() {
+            return delta.to_string();
+        }
+        self.text.push_str(delta);
+        let pending = &self.text[self.emitted..];
+        if let Some(end) = find_stop(pending, &self.sequences) {
+            self.stopped = true;
+            return pending[..end].to_string();
+        }
+        // holds back what could be the start of a stop sequence split across deltas
+        let longest = self.sequences.iter().map(String::len).max().unwrap_or(1);
+        let mut end = self
+            .text
+            .len()
+            .saturating_sub(longest - 1)
+            .max(self.emitted);
+        while !self.text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let safe = self.text[self.emitted..end].to_string();
+        self.emitted = end;
+        safe
+    }
+
+    fn finish(&mut self) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.stopped = true;
+        self.text[self.emitted..].to_string()
+    }
+}
+
+fn delta_chunk(text: String) -> Vec<CompletionChunk> {
+    if text.is_empty() {
+        Vec::new()
+    } else {
+        vec![CompletionChunk::Delta(text)]
+    }
+}
+
+fn find_stop(text: &str, sequences: &[String]) -> Option<usize> {
+    sequences
+        .iter()
+        .filter_map(|sequence| text.find(sequence.as_str()))
+        .min()
+}
+
+fn local_stop_sequences(stop: &[String]) -> Vec<String> {
+    stop.iter()
+        .filter(|sequence| !sequence.is_empty() && sequence.trim().is_empty())
+        .cloned()
+        .collect()
+}
+
+#[derive(Serialize)]
+struct AnthropicToolChoice {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    name: String,
+}
+
+pub struct AnthropicProvider;
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    async fn complete(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, LLMError> {
+        completion_anthropic(model.clone(), messages, Some(options)).await
+    }
+
+    async fn stream(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<CompletionStream, LLMError> {
+        stream_anthropic(model.clone(), messages, Some(options)).await
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities {
+            images: true,
+            streaming: true,
+            tools: true,
+            candidates: false,
+            system_messages: SystemMessages::Leading,
+            // the API combines consecutive turns itself, and the cache breakpoints stay on the
+            // messages they were set on
+            alternating_roles: false,
+            user_first: true,
+            trailing_assistant: true,
+        }
+    }
 }
 
 pub async fn completion_anthropic(
     model: Model,
     messages: &[Message],
     options: Option<&CompletionOptions>,
-) -> Result<String, LLMError> {
+) -> Result<Completion, LLMError> {
+    let response = send_anthropic_request(model, messages, options, false).await?;
+    let response_body: AnthropicResponse = match response.json().await {
+        Ok(body) => body,
+        Err(e) => return Err(LLMError::RequestError(e)),
+    };
+
+    if response_body.content.is_empty() {
+        return Err(LLMError::EmptyResponse);
+    }
+    // structured output is requested as a forced tool call, so its input is the response
+    let content = match options.and_then(|opt| opt.response_schema.as_ref()) {
+        Some(response_schema) => response_body
+            .content
+            .into_iter()
+            .map(|block| match block {
+                ContentBlock::ToolUse { name, input, .. } if name == response_schema.name => {
+                    ContentBlock::Text {
+                        text: input.to_string(),
+                    }
+                }
+                block => block,
+            })
+            .collect(),
+        None => {
+            let stop_sequences = options
+                .map(|opt| local_stop_sequences(&opt.stop))
+                .unwrap_or_default();
+            let mut content = Vec::new();
+            for block in response_body.content {
+                if let ContentBlock::Text { text } = &block {
+                    if let Some(end) = find_stop(text, &stop_sequences) {
+                        content.push(ContentBlock::Text {
+                            text: text[..end].to_string(),
+                        });
+                        break;
+                    }
+                }
+                content.push(block);
+            }
+            content
+        }
+    };
+    Ok(Completion {
+        content,
+        alternatives: Vec::new(),
+        usage: response_body.usage.into(),
+    })
+}
+
+pub async fn stream_anthropic(
+    model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+) -> Result<CompletionStream, LLMError> {
+    let response = send_anthropic_request(model, messages, options, true).await?;
+    let structured = options.is_some_and(|opt| opt.response_schema.is_some());
+    // the input token counts arrive in `message_start`, the output token count in `message_delta`
+    Ok(completion_stream(
+        sse_events(response),
+        (Usage::default(), LocalStop::new(options)),
+        move |(input_usage, local_stop), event| {
+            let event: AnthropicStreamEvent = parse_json_event(&event.data)?;
+            match event {
+                AnthropicStreamEvent::MessageStart { message } => {
+                    *input_usage = message.usage.into();
+                    Ok(Vec::new())
+                }
+                AnthropicStreamEvent::ContentBlockDelta {
+                    delta: AnthropicDelta::TextDelta { text },
+                } => Ok(delta_chunk(local_stop.push(&text))),
+                AnthropicStreamEvent::ContentBlockDelta {
+                    delta: AnthropicDelta::InputJsonDelta { partial_json },
+                } if structured => Ok(vec![CompletionChunk::Delta(partial_json)]),
+                AnthropicStreamEvent::ContentBlockDelta {
+                    delta: AnthropicDelta::ThinkingDelta { thinking },
+                } => Ok(vec![CompletionChunk::ThinkingDelta(thinking)]),
+                AnthropicStreamEvent::MessageDelta { usage } => {
+                    let mut chunks = delta_chunk(local_stop.finish());
+                    chunks.push(CompletionChunk::Usage(Usage {
+                        output_tokens: usage.output_tokens,
+                        ..*input_usage
+                    }));
+                    Ok(chunks)
+                }
+                AnthropicStreamEvent::Error { error } => Err(errors::classify(ProviderError {
+                    provider: "Anthropic".to_string(),
+                    status: stream_error_status(&error.type_),
+                    code: Some(error.type_),
+                    message: error.message,
+                    retry_after: None,
+                })),
+                _ => Ok(Vec::new()),
+            }
+        },
+    ))
+}
+
+async fn send_anthropic_request(
+    model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+    stream: bool,
+) -> Result<reqwest::Response, LLMError> {
     let (system_content, messages) =
         if !messages.is_empty() && matches!(messages[0].role, crate::llm::Role::System) {
             let content = match &messages[0].content {
@@ -49,6 +398,14 @@ pub async fn completion_anthropic(
         } else {
             (None, messages)
         };
+    // the breakpoints index the messages including the system prompt, which is sent separately
+    let system_offset = usize::from(system_content.is_some());
+    let cache_breakpoints: Vec<usize> = options
+        .map(|opt| opt.cache_breakpoints.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|idx| idx.checked_sub(system_offset))
+        .collect();
 
     let anthropic_messages: Vec<_> = messages
         .iter()
@@ -68,31 +425,94 @@ pub async fn completion_anthropic(
         })
         .collect();
 
+    // the cache control goes on the last block of a message, which ends the cached prefix
     let anthropic_messages: Vec<_> = anthropic_messages
         .iter()
-        .map(|(role, content)| AnthropicMessage { role, content })
+        .enumerate()
+        .map(|(idx, (role, content))| AnthropicMessage {
+            role,
+            content: content
+                .iter()
+                .enumerate()
+                .map(|(block_idx, block)| AnthropicContentBlock {
+                    block,
+                    cache_control: (block_idx + 1 == content.len()
+                        && cache_breakpoints.contains(&idx))
+                    .then_some(AnthropicCacheControl { type_: "ephemeral" }),
+                })
+                .collect(),
+        })
         .collect();
+    let thinking_budget = options.and_then(|opt| opt.thinking_budget);
+    let mut tools = options.map(|opt| opt.tools.clone()).unwrap_or_default();
+    let tool_choice = match options.and_then(|opt| opt.response_schema.as_ref()) {
+        Some(response_schema) => {
+            tools.push(Tool {
+                name: response_schema.name.clone(),
+                description: "Respond with an object that follows this schema.".to_string(),
+                input_schema: response_schema.schema.clone(),
+            });
+            // a forced tool call is not allowed with thinking, so the model is left to choose it
+            thinking_budget.is_none().then(|| AnthropicToolChoice {
+                type_: "tool",
+                name: response_schema.name.clone(),
+            })
+        }
+        None => None,
+    };
+    let mut max_tokens = options
+        .map(|opt| opt.max_completion_tokens)
+        .filter(|&t| t != 0)
+        .unwrap_or(DEFAULT_ANTHROPIC_MAX_COMPLETION_TOKENS);
+    // the thinking budget counts towards max_tokens, which has to leave room for the answer
+    // without going over the model's output limit
+    if let Some(budget_tokens) = thinking_budget {
+        if max_tokens <= budget_tokens as i32 {
+            max_tokens = budget_tokens as i32 + DEFAULT_ANTHROPIC_MAX_COMPLETION_TOKENS;
+        }
+        if let Some(info) = model_info(&Provider::Anthropic, &model) {
+            max_tokens = max_tokens.min(info.max_output_tokens as i32);
+        }
+    }
     let req_body = AnthropicRequest {
         model: model.to_string(),
         messages: anthropic_messages,
         system: system_content,
-        max_tokens: Some(
-            options
-                .map(|opt| opt.max_completion_tokens)
-                .filter(|&t| t != 0)
-                .unwrap_or(DEFAULT_ANTHROPIC_MAX_COMPLETION_TOKENS),
-        ),
-        temperature: options.and_then(|opt| (opt.temperature != 0.0).then_some(opt.temperature)),
+        max_tokens: Some(max_tokens),
+        // thinking only runs at the default temperature and top_p
+        temperature: options
+            .filter(|_| thinking_budget.is_none())
+            .and_then(|opt| opt.temperature),
+        top_p: options
+            .filter(|_| thinking_budget.is_none())
+            .and_then(|opt| opt.top_p),
+        stop_sequences: options
+            .map(|opt| {
+                opt.stop
+                    .iter()
+                    .filter(|sequence| !sequence.trim().is_empty())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default(),
+        stream: stream.then_some(true),
+        tools,
+        tool_choice,
+        thinking: thinking_budget.map(|budget_tokens| AnthropicThinking {
+            type_: "enabled",
+            budget_tokens,
+        }),
     };
 
-    let api_key = match env::var("ANTHROPIC_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            return Err(LLMError::RequestBuildingError(
-                "ANTHROPIC_API_KEY environment variable not set".to_string(),
-            ))
-        }
-    };
+    let endpoint = endpoint_config("anthropic");
+    let api_key = endpoint.api_key("ANTHROPIC_API_KEY")?;
+    let url = format!(
+        "{}/messages",
+        endpoint.base_url(
+            options.and_then(|opt| opt.server_endpoint.as_deref()),
+            ANTHROPIC_API_BASE_URL
+        )
+    );
 
     let mut headers = HeaderMap::new();
     let api_header = match HeaderValue::from_str(&api_key) {
@@ -102,42 +522,75 @@ pub async fn completion_anthropic(
     headers.insert("x-api-key", api_header);
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
     headers.insert("anthropic-version", HeaderValue::from_static("2023-06-01"));
+    endpoint.add_headers(&mut headers)?;
 
-    let client = reqwest::Client::new();
-    let response = match client
-        .post(ANTHROPIC_API_URL)
-        .headers(headers)
-        .json(&req_body)
-        .send()
-        .await
+    let response = match send_with_retries(|client| {
+        client.post(&url).headers(headers.clone()).json(&req_body)
+    })
+    .await
     {
         Ok(resp) => resp,
         Err(e) => return Err(LLMError::RequestError(e)),
     };
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unable to read error response".to_string());
-        return Err(LLMError::Other(
-            format!(
-                "Anthropic API request failed with status {}: {}",
-                status, error_text
-            )
-            .into(),
-        ));
+    if !response.status().is_success() {
+        return Err(errors::from_response("Anthropic", response).await);
     }
+    Ok(response)
+}
 
-    let response_body: AnthropicResponse = match response.json().await {
-        Ok(body) => body,
-        Err(e) => return Err(LLMError::RequestError(e)),
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_stop(stop: &[&str]) -> LocalStop {
+        let request = crate::llm::CompletionBuilder::new()
+            .stop(stop.iter().map(|sequence| sequence.to_string()).collect())
+            .build();
+        LocalStop::new(Some(&request.options))
+    }
+
+    fn push_all(local_stop: &mut LocalStop, deltas: &[&str]) -> String {
+        let mut text: String = deltas.iter().map(|delta| local_stop.push(delta)).collect();
+        text.push_str(&local_stop.finish());
+        text
+    }
 
-    response_body
-        .content
-        .first()
-        .map(|content| content.text.clone())
-        .ok_or(LLMError::EmptyResponse)
+    #[test]
+    fn only_whitespace_stop_sequences_are_applied_locally() {
+        let stop: Vec<String> = ["\n", "", "END", " \t", "\n\n"]
+            .iter()
+            .map(|sequence| sequence.to_string())
+            .collect();
+        assert_eq!(local_stop_sequences(&stop), ["\n", " \t", "\n\n"]);
+    }
+
+    #[test]
+    fn stops_at_a_newline() {
+        let mut local_stop = with_stop(&["\n"]);
+        assert_eq!(
+            push_all(&mut local_stop, &["first ", "line\nsecond", " line"]),
+            "first line"
+        );
+    }
+
+    #[test]
+    fn stops_at_a_sequence_split_across_deltas() {
+        let mut local_stop = with_stop(&["\n\n"]);
+        assert_eq!(local_stop.push("one\n"), "one");
+        assert_eq!(local_stop.push("two\n"), "\ntwo");
+        assert_eq!(local_stop.push("\nthree"), "");
+        assert_eq!(local_stop.finish(), "");
+    }
+
+    #[test]
+    fn passes_text_without_a_stop_sequence_through() {
+        let mut local_stop = with_stop(&["\n\n"]);
+        assert_eq!(
+            push_all(&mut local_stop, &["héllo", " wörld\n"]),
+            "héllo wörld\n"
+        );
+        let mut no_stop = with_stop(&["END"]);
+        assert_eq!(no_stop.push("text END"), "text END");
+    }
 }
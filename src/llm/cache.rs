@@ -0,0 +1,227 @@
+use crate::llm::custom::CustomDialect;
+use crate::llm::{
+    CompletionOptions, ContentBlock, Message, Model, Provider, ReasoningEffort, ResponseSchema,
+    Tool, Usage,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    // entries older than this are treated as misses and deleted
+    pub ttl: Duration,
+    // the oldest entries are deleted once the store grows past this
+    pub max_size_bytes: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        let cache_home = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        Self {
+            dir: cache_home.join("captain").join("completions"),
+            ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            max_size_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+// what is stored for each request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CachedCompletion {
+    pub content: Vec<ContentBlock>,
+    #[serde(default)]
+    pub alternatives: Vec<Vec<ContentBlock>>,
+    pub usage: Usage,
+    // the provider and model that produced the completion, which may be a fallback
+    pub provider: Provider,
+    pub model: String,
+}
+
+// everything that can change the completion
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    candidates: Vec<(&'a str, String)>,
+    messages: &'a [Message],
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    stop: &'a [String],
+    seed: Option<u64>,
+    candidate_count: Option<u32>,
+    max_completion_tokens: i32,
+    server_endpoint: &'a Option<String>,
+    custom_server_endpoint: &'a Option<String>,
+    custom_model: &'a Option<String>,
+    custom_dialect: &'a CustomDialect,
+    tools: &'a [Tool],
+    response_schema: &'a Option<ResponseSchema>,
+    thinking_budget: Option<u32>,
+    reasoning_effort: Option<ReasoningEffort>,
+    prefill: bool,
+}
+
+static CONFIG: OnceLock<CacheConfig> = OnceLock::new();
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static EVICTIONS: AtomicU64 = AtomicU64::new(0);
+
+// must be called before the first cached request, returns the config back if it is too late
+pub fn configure(config: CacheConfig) -> Result<(), CacheConfig> {
+    CONFIG.set(config)
+}
+
+fn config() -> &'static CacheConfig {
+    CONFIG.get_or_init(CacheConfig::default)
+}
+
+pub fn stats() -> CacheStats {
+    CacheStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        evictions: EVICTIONS.load(Ordering::Relaxed),
+    }
+}
+
+pub(crate) fn cache_key(
+    candidates: &[(Provider, Model)],
+    messages: &[Message],
+    options: &CompletionOptions,
+) -> String {
+    let key = CacheKey {
+        candidates: candidates
+            .iter()
+            .map(|(provider, model)| (provider.name(), model.to_string()))
+            .collect(),
+        messages,
+        temperature: options.temperature,
+        top_p: options.top_p,
+        stop: &options.stop,
+        seed: options.seed,
+        candidate_count: options.candidate_count,
+        max_completion_tokens: options.max_completion_tokens,
+        server_endpoint: &options.server_endpoint,
+        custom_server_endpoint: &options.custom_server_endpoint,
+        custom_model: &options.custom_model,
+        custom_dialect: &options.custom_dialect,
+        tools: &options.tools,
+        response_schema: &options.response_schema,
+        thinking_budget: options.thinking_budget,
+        reasoning_effort: options.reasoning_effort,
+        prefill: options.prefill,
+    };
+    let bytes = serde_json::to_vec(&key).unwrap_or_default();
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+fn entry_path(key: &str) -> PathBuf {
+    config().dir.join(format!("{key}.json"))
+}
+
+// read errors and expired or corrupt entries count as misses
+pub(crate) async fn get(key: &str) -> Option<CachedCompletion> {
+    let path = entry_path(key);
+    let cached = match tokio::fs::metadata(&path).await {
+        Ok(metadata) if is_expired(&metadata) => {
+            let _ = tokio::fs::remove_file(&path).await;
+            EVICTIONS.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+        Ok(_) => match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes).ok(),
+            Err(_) => None,
+        },
+        Err(_) => None,
+    };
+    match cached {
+        Some(_) => HITS.fetch_add(1, Ordering::Relaxed),
+        None => MISSES.fetch_add(1, Ordering::Relaxed),
+    };
+    cached
+}
+
+// failing to write the cache never fails the request
+pub(crate) async fn put(key: &str, completion: &CachedCompletion) {
+    let config = config();
+    if tokio::fs::create_dir_all(&config.dir).await.is_err() {
+        return;
+    }
+    let bytes = match serde_json::to_vec(completion) {
+        Ok(bytes) => bytes,
+        Err(_) => return,
+    };
+    // written to a temporary file first so a concurrent reader never sees half an entry
+    let path = entry_path(key);
+    let tmp_path = path.with_extension("tmp");
+    if tokio::fs::write(&tmp_path, bytes).await.is_err() {
+        return;
+    }
+    if tokio::fs::rename(&tmp_path, &path).await.is_err() {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return;
+    }
+    prune().await;
+}
+
+fn is_expired(metadata: &std::fs::Metadata) -> bool {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > config().ttl)
+}
+
+// deletes expired entries, then the oldest ones until the store fits in `max_size_bytes`
+async fn prune() {
+    let config = config();
+    let mut dir = match tokio::fs::read_dir(&config.dir).await {
+        Ok(dir) => dir,
+        Err(_) => return,
+    };
+    let mut entries = Vec::new();
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        let path = entry.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let metadata = match entry.metadata().await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if is_expired(&metadata) {
+            if tokio::fs::remove_file(&path).await.is_ok() {
+                EVICTIONS.fetch_add(1, Ordering::Relaxed);
+            }
+            continue;
+        }
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        entries.push((modified, metadata.len(), path));
+    }
+    let mut total_size: u64 = entries.iter().map(|(_, size, _)| size).sum();
+    if total_size <= config.max_size_bytes {
+        return;
+    }
+    entries.sort_by_key(|(modified, _, _)| *modified);
+    for (_, size, path) in entries {
+        if total_size <= config.max_size_bytes {
+            break;
+        }
+        if tokio::fs::remove_file(&path).await.is_ok() {
+            EVICTIONS.fetch_add(1, Ordering::Relaxed);
+            total_size -= size;
+        }
+    }
+}
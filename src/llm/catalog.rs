@@ -0,0 +1,265 @@
+use crate::llm::pricing::ModelPrice;
+use crate::llm::{Model, Provider};
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+// what a model accepts, keyed by the provider and the model id sent to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub id: String,
+    pub provider: Provider,
+    pub vision: bool,
+    // total tokens (prompt and completion) that the model accepts
+    pub context_window: u32,
+    pub max_output_tokens: u32,
+    // `None` when the price is unknown
+    pub price: Option<ModelPrice>,
+    // models without one take their instructions in the first user message
+    pub system_prompt: bool,
+    // OpenAI's o-series: no sampling parameters, `max_completion_tokens` rather than `max_tokens`
+    // and a `developer` role in place of `system`
+    pub reasoning: bool,
+}
+
+// a `ModelInfo` that can be written as a constant
+struct BuiltinModel {
+    id: &'static str,
+    provider: Provider,
+    vision: bool,
+    context_window: u32,
+    max_output_tokens: u32,
+    price: ModelPrice,
+    system_prompt: bool,
+    reasoning: bool,
+}
+
+// from the providers' public model and price lists
+const BUILTIN_MODELS: [BuiltinModel; 18] = [
+    BuiltinModel {
+        id: "gpt-4o",
+        provider: Provider::OpenAI,
+        vision: true,
+        context_window: 128_000,
+        max_output_tokens: 16_384,
+        price: ModelPrice::new(2.5, 10.0),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "gpt-4o-mini",
+        provider: Provider::OpenAI,
+        vision: true,
+        context_window: 128_000,
+        max_output_tokens: 16_384,
+        price: ModelPrice::new(0.15, 0.6),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "o1",
+        provider: Provider::OpenAI,
+        vision: true,
+        context_window: 200_000,
+        max_output_tokens: 100_000,
+        price: ModelPrice::new(15.0, 60.0),
+        system_prompt: true,
+        reasoning: true,
+    },
+    BuiltinModel {
+        id: "o1-mini",
+        provider: Provider::OpenAI,
+        vision: false,
+        context_window: 128_000,
+        max_output_tokens: 65_536,
+        price: ModelPrice::new(1.1, 4.4),
+        system_prompt: false,
+        reasoning: true,
+    },
+    BuiltinModel {
+        id: "o3-mini",
+        provider: Provider::OpenAI,
+        vision: false,
+        context_window: 200_000,
+        max_output_tokens: 100_000,
+        price: ModelPrice::new(1.1, 4.4),
+        system_prompt: true,
+        reasoning: true,
+    },
+    BuiltinModel {
+        id: "claude-3-5-sonnet-latest",
+        provider: Provider::Anthropic,
+        vision: true,
+        context_window: 200_000,
+        max_output_tokens: 8_192,
+        price: ModelPrice::new(3.0, 15.0),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "claude-3-7-sonnet-latest",
+        provider: Provider::Anthropic,
+        vision: true,
+        context_window: 200_000,
+        max_output_tokens: 64_000,
+        price: ModelPrice::new(3.0, 15.0),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "claude-3-5-haiku-latest",
+        provider: Provider::Anthropic,
+        vision: false,
+        context_window: 200_000,
+        max_output_tokens: 8_192,
+        price: ModelPrice::new(0.8, 4.0),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "gemini-2.0-flash-exp",
+        provider: Provider::Google,
+        vision: true,
+        context_window: 1_048_576,
+        max_output_tokens: 8_192,
+        price: ModelPrice::new(0.1, 0.4),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "gemini-1.5-flash",
+        provider: Provider::Google,
+        vision: true,
+        context_window: 1_048_576,
+        max_output_tokens: 8_192,
+        price: ModelPrice::new(0.075, 0.3),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "gemini-1.5-flash-8b",
+        provider: Provider::Google,
+        vision: true,
+        context_window: 1_048_576,
+        max_output_tokens: 8_192,
+        price: ModelPrice::new(0.0375, 0.15),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "gemini-1.5-pro",
+        provider: Provider::Google,
+        vision: true,
+        context_window: 2_097_152,
+        max_output_tokens: 8_192,
+        price: ModelPrice::new(1.25, 5.0),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "llama-v3p2-1b-instruct",
+        provider: Provider::Fireworks,
+        vision: false,
+        context_window: 131_072,
+        max_output_tokens: 16_384,
+        price: ModelPrice::new(0.1, 0.1),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "llama-v3p2-3b-instruct",
+        provider: Provider::Fireworks,
+        vision: false,
+        context_window: 131_072,
+        max_output_tokens: 16_384,
+        price: ModelPrice::new(0.1, 0.1),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "llama-v3p1-8b-instruct",
+        provider: Provider::Fireworks,
+        vision: false,
+        context_window: 131_072,
+        max_output_tokens: 16_384,
+        price: ModelPrice::new(0.2, 0.2),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "llama-v3p2-11b-vision-instruct",
+        provider: Provider::Fireworks,
+        vision: true,
+        context_window: 131_072,
+        max_output_tokens: 16_384,
+        price: ModelPrice::new(0.2, 0.2),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "llama-v3p1-70b-instruct",
+        provider: Provider::Fireworks,
+        vision: false,
+        context_window: 131_072,
+        max_output_tokens: 16_384,
+        price: ModelPrice::new(0.9, 0.9),
+        system_prompt: true,
+        reasoning: false,
+    },
+    BuiltinModel {
+        id: "llama-v3p1-405b-instruct",
+        provider: Provider::Fireworks,
+        vision: false,
+        context_window: 131_072,
+        max_output_tokens: 16_384,
+        price: ModelPrice::new(3.0, 3.0),
+        system_prompt: true,
+        reasoning: false,
+    },
+];
+
+type Catalog = HashMap<(Provider, String), ModelInfo>;
+
+static CATALOG: OnceLock<RwLock<Catalog>> = OnceLock::new();
+
+fn catalog() -> &'static RwLock<Catalog> {
+    CATALOG.get_or_init(|| {
+        RwLock::new(
+            BUILTIN_MODELS
+                .into_iter()
+                .map(|model| {
+                    let info = ModelInfo {
+                        id: model.id.to_string(),
+                        provider: model.provider,
+                        vision: model.vision,
+                        context_window: model.context_window,
+                        max_output_tokens: model.max_output_tokens,
+                        price: Some(model.price),
+                        system_prompt: model.system_prompt,
+                        reasoning: model.reasoning,
+                    };
+                    ((info.provider.clone(), info.id.clone()), info)
+                })
+                .collect(),
+        )
+    })
+}
+
+// registering a built-in model id of the same provider replaces its entry
+pub fn register_model(info: ModelInfo) {
+    catalog()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert((info.provider.clone(), info.id.clone()), info);
+}
+
+// `None` for custom servers and for model ids that are not registered for `provider`
+pub fn model_info(provider: &Provider, model: &Model) -> Option<ModelInfo> {
+    if let Model::Custom = model {
+        return None;
+    }
+    catalog()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&(provider.clone(), model.to_string()))
+        .cloned()
+}
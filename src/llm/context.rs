@@ -0,0 +1,170 @@
+use crate::llm::{CompletionOptions, ContentBlock, ImageSource, Message, MessageContent, Model};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use image::imageops::FilterType;
+
+// what is given up, in this order, when a request does not fit in the model's context; each
+// step applies to what the previous ones left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContextReduction {
+    // the images of half of the messages that have any, in `image_drop_order`
+    FewerImages,
+    // the remaining images at half their width and height
+    DownscaledImages,
+    // the remaining images replaced by their `image_descriptions`
+    TextDescriptions,
+}
+
+pub(crate) const CONTEXT_REDUCTIONS: [ContextReduction; 3] = [
+    ContextReduction::FewerImages,
+    ContextReduction::DownscaledImages,
+    ContextReduction::TextDescriptions,
+];
+
+// the messages after the next reduction that changes anything, or `None` once there is nothing
+// left to give up; images are removed from their messages rather than removing the messages, so
+// the indexes in the options still apply
+pub(crate) fn reduce_context(
+    model: &Model,
+    messages: &[Message],
+    reductions: &mut impl Iterator<Item = ContextReduction>,
+    options: &CompletionOptions,
+) -> Option<Vec<Message>> {
+    for reduction in reductions {
+        let mut reduced = messages.to_vec();
+        let summary = match reduction {
+            ContextReduction::FewerImages => drop_images(&mut reduced, options),
+            ContextReduction::DownscaledImages => downscale_images(&mut reduced),
+            ContextReduction::TextDescriptions => describe_images(&mut reduced, options),
+        };
+        if let Some(summary) = summary {
+            println!(
+                "[warning] The request does not fit in the context of {}, retrying with {}",
+                model, summary
+            );
+            return Some(reduced);
+        }
+    }
+    None
+}
+
+fn image_messages(messages: &[Message]) -> Vec<usize> {
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| match &message.content {
+            MessageContent::Text(_) => false,
+            MessageContent::MultiContent(blocks) => blocks
+                .iter()
+                .any(|block| matches!(block, ContentBlock::Image { .. })),
+        })
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+fn drop_images(messages: &mut [Message], options: &CompletionOptions) -> Option<String> {
+    let with_images = image_messages(messages);
+    if with_images.is_empty() {
+        return None;
+    }
+    let mut order: Vec<usize> = Vec::new();
+    for &idx in options.image_drop_order.iter().chain(&with_images) {
+        if with_images.contains(&idx) && !order.contains(&idx) {
+            order.push(idx);
+        }
+    }
+    let num_dropped = with_images.len().div_ceil(2);
+    for &idx in &order[..num_dropped] {
+        replace_images(&mut messages[idx], |_| None);
+    }
+    Some(format!(
+        "the images of {} of {} messages dropped",
+        num_dropped,
+        with_images.len()
+    ))
+}
+
+fn downscale_images(messages: &mut [Message]) -> Option<String> {
+    let mut num_downscaled = 0;
+    for message in messages.iter_mut() {
+        replace_images(message, |source| match downscale(source) {
+            Some(source) => {
+                num_downscaled += 1;
+                Some(ContentBlock::Image { source })
+            }
+            None => Some(ContentBlock::Image {
+                source: source.clone(),
+            }),
+        });
+    }
+    match num_downscaled {
+        0 => None,
+        n => Some(format!("{} images downscaled to half their size", n)),
+    }
+}
+
+// `None` for images that cannot be decoded or are already tiny
+fn downscale(source: &ImageSource) -> Option<ImageSource> {
+    let bytes = BASE64.decode(&source.data).ok()?;
+    let image = image::load_from_memory(&bytes).ok()?;
+    if image.width() < 64 || image.height() < 64 {
+        return None;
+    }
+    let resized = image
+        .resize(image.width() / 2, image.height() / 2, FilterType::Triangle)
+        .to_rgb8();
+    let mut buffer = Vec::new();
+    let mut encoder = image::codecs::jpeg::JpegEncoder::new(&mut buffer);
+    encoder.encode_image(&resized).ok()?;
+    Some(ImageSource {
+        source_type: "base64".to_string(),
+        media_type: "image/jpeg".to_string(),
+        data: BASE64.encode(&buffer),
+    })
+}
+
+fn describe_images(messages: &mut [Message], options: &CompletionOptions) -> Option<String> {
+    let with_images = image_messages(messages);
+    if with_images.is_empty() {
+        return None;
+    }
+    let mut num_described = 0;
+    for &idx in &with_images {
+        let description = options.image_descriptions.get(&idx);
+        if description.is_some() {
+            num_described += 1;
+        }
+        replace_images(&mut messages[idx], |_| {
+            description.map(|description| ContentBlock::Text {
+                text: description.clone(),
+            })
+        });
+    }
+    Some(format!(
+        "the images of {} messages replaced by text, {} of them with a description",
+        with_images.len(),
+        num_described
+    ))
+}
+
+// messages left without any content get a placeholder, since the providers reject empty ones
+fn replace_images(
+    message: &mut Message,
+    mut replacement: impl FnMut(&ImageSource) -> Option<ContentBlock>,
+) {
+    let blocks = match &mut message.content {
+        MessageContent::Text(_) => return,
+        MessageContent::MultiContent(blocks) => blocks,
+    };
+    *blocks = blocks
+        .drain(..)
+        .filter_map(|block| match block {
+            ContentBlock::Image { source } => replacement(&source),
+            block => Some(block),
+        })
+        .collect();
+    if blocks.is_empty() {
+        blocks.push(ContentBlock::Text {
+            text: "[image omitted]".to_string(),
+        });
+    }
+}
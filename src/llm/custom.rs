@@ -1,21 +1,95 @@
-use crate::llm::{CompletionOptions, LLMError, Message, MessageContent, Model};
+use crate::http_client::send_with_retries;
+use crate::llm::endpoint::endpoint_config;
+use crate::llm::errors;
+use crate::llm::openai::{
+    build_openai_messages, build_openai_response_format, openai_completion_stream,
+    read_openai_response, OpenAIMessage, OpenAIResponseFormat, StreamOptions,
+};
+use crate::llm::provider::{LlmProvider, ProviderCapabilities, SystemMessages};
+use crate::llm::streaming::{completion_stream, lines, parse_json_event};
+use crate::llm::{
+    Completion, CompletionChunk, CompletionOptions, CompletionStream, ContentBlock, LLMError,
+    Message, MessageContent, Model, Role, Usage,
+};
+use async_trait::async_trait;
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
 
+// the wire format spoken by the server at `custom_server_endpoint`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CustomDialect {
+    // Ollama's native `/api/chat`
+    #[default]
+    #[serde(rename = "ollama")]
+    Ollama,
+    // `/v1/chat/completions`, e.g. vLLM or the llama.cpp server
+    #[serde(rename = "openai")]
+    OpenAI,
+}
+
+#[derive(Serialize)]
+struct OllamaRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    options: OllamaOptions,
+    // JSON schema that constrains the response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct OllamaMessage {
+    role: &'static str,
+    content: String,
+    // base64 encoded images without the data URI prefix
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<i32>,
+}
+
 #[derive(Serialize)]
-struct CustomRequest<'a> {
+struct OpenAICompatibleRequest {
     model: String,
-    messages: &'a [Message],
+    messages: Vec<OpenAIMessage>,
     stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<OpenAIResponseFormat>,
+    // without it the streams report no usage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
 }
 
 #[derive(Deserialize)]
 struct CustomResponse {
     message: CustomMessage,
+    #[serde(default)]
+    prompt_eval_count: u32,
+    #[serde(default)]
+    eval_count: u32,
 }
 
 #[derive(Deserialize)]
@@ -23,19 +97,119 @@ struct CustomMessage {
     content: String,
 }
 
+#[derive(Deserialize)]
+struct CustomStreamResponse {
+    message: Option<CustomMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    prompt_eval_count: u32,
+    #[serde(default)]
+    eval_count: u32,
+}
+
+pub struct CustomProvider;
+
+#[async_trait]
+impl LlmProvider for CustomProvider {
+    async fn complete(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, LLMError> {
+        completion_custom(model.clone(), messages, Some(options)).await
+    }
+
+    async fn stream(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<CompletionStream, LLMError> {
+        stream_custom(model.clone(), messages, Some(options)).await
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities {
+            images: true,
+            streaming: true,
+            tools: false,
+            candidates: false,
+            system_messages: SystemMessages::Leading,
+            // the chat templates of many open models reject anything else
+            alternating_roles: true,
+            user_first: true,
+            trailing_assistant: false,
+        }
+    }
+}
+
 pub async fn completion_custom(
-    _model: Model,
+    model: Model,
     messages: &[Message],
     options: Option<&CompletionOptions>,
-) -> Result<String, LLMError> {
-    // Check for images
-    if messages
-        .iter()
-        .any(|msg| matches!(msg.content, MessageContent::MultiContent(_)))
-    {
-        return Err(LLMError::ImagesNotSupported);
+) -> Result<Completion, LLMError> {
+    let (dialect, response) = send_custom_request(model, messages, options, false).await?;
+    if dialect == CustomDialect::OpenAI {
+        return read_openai_response(response).await;
     }
+    let response_body: CustomResponse = match response.json().await {
+        Ok(body) => body,
+        Err(e) => return Err(LLMError::RequestError(e)),
+    };
 
+    Ok(Completion {
+        content: vec![ContentBlock::Text {
+            text: response_body.message.content,
+        }],
+        alternatives: Vec::new(),
+        usage: Usage {
+            input_tokens: response_body.prompt_eval_count,
+            output_tokens: response_body.eval_count,
+            ..Default::default()
+        },
+    })
+}
+
+pub async fn stream_custom(
+    model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+) -> Result<CompletionStream, LLMError> {
+    let (dialect, response) = send_custom_request(model, messages, options, true).await?;
+    if dialect == CustomDialect::OpenAI {
+        return Ok(openai_completion_stream(response));
+    }
+    // Ollama streams newline-delimited JSON objects, the last one has `done` set and the usage
+    Ok(completion_stream(lines(response), (), |_, line| {
+        if line.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        let chunk: CustomStreamResponse = parse_json_event(&line)?;
+        let mut chunks = Vec::new();
+        if let Some(message) = chunk.message {
+            if !message.content.is_empty() {
+                chunks.push(CompletionChunk::Delta(message.content));
+            }
+        }
+        if chunk.done {
+            chunks.push(CompletionChunk::Usage(Usage {
+                input_tokens: chunk.prompt_eval_count,
+                output_tokens: chunk.eval_count,
+                ..Default::default()
+            }));
+        }
+        Ok(chunks)
+    }))
+}
+
+async fn send_custom_request(
+    _model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+    stream: bool,
+) -> Result<(CustomDialect, reqwest::Response), LLMError> {
     let Some(options) = options else {
         return Err(LLMError::RequestBuildingError(
             "completion options not set".to_string(),
@@ -60,47 +234,107 @@ pub async fn completion_custom(
         }
     };
 
-    let req_body = CustomRequest {
-        model: custom_model.clone(),
-        messages: messages,
-        stream: false,
-        temperature: (options.temperature != 0.0).then_some(options.temperature),
-        max_tokens: (options.max_completion_tokens != 0).then_some(options.max_completion_tokens),
+    let max_tokens = (options.max_completion_tokens != 0).then_some(options.max_completion_tokens);
+    let req_body = match options.custom_dialect {
+        CustomDialect::Ollama => serde_json::to_value(OllamaRequest {
+            model: custom_model.clone(),
+            messages: build_ollama_messages(messages),
+            stream,
+            options: OllamaOptions {
+                temperature: options.temperature,
+                top_p: options.top_p,
+                stop: options.stop.clone(),
+                seed: options.seed,
+                num_predict: max_tokens,
+            },
+            format: options
+                .response_schema
+                .as_ref()
+                .map(|response_schema| response_schema.schema.clone()),
+        }),
+        CustomDialect::OpenAI => serde_json::to_value(OpenAICompatibleRequest {
+            model: custom_model.clone(),
+            messages: build_openai_messages(messages),
+            stream,
+            temperature: options.temperature,
+            top_p: options.top_p,
+            stop: options.stop.clone(),
+            seed: options.seed,
+            max_tokens,
+            response_format: options
+                .response_schema
+                .as_ref()
+                .map(build_openai_response_format),
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+        }),
+    };
+    let req_body = match req_body {
+        Ok(body) => body,
+        Err(e) => return Err(LLMError::RequestBuildingError(e.to_string())),
     };
 
     let mut headers = HeaderMap::new();
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    // e.g. the credentials of a gateway in front of the server
+    endpoint_config("custom").add_headers(&mut headers)?;
 
-    let client = reqwest::Client::new();
-    let response = match client
-        .post(custom_endpoint)
-        .headers(headers)
-        .json(&req_body)
-        .send()
-        .await
+    let response = match send_with_retries(|client| {
+        client
+            .post(custom_endpoint)
+            .headers(headers.clone())
+            .json(&req_body)
+    })
+    .await
     {
         Ok(resp) => resp,
         Err(e) => return Err(LLMError::RequestError(e)),
     };
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unable to read error response".to_string());
-        return Err(LLMError::Other(
-            format!(
-                "Custom API request failed with status {}: {}",
-                status, error_text
-            )
-            .into(),
-        ));
+    if !response.status().is_success() {
+        return Err(errors::from_response("Custom", response).await);
     }
+    Ok((options.custom_dialect, response))
+}
 
-    let response_body: CustomResponse = match response.json().await {
-        Ok(body) => body,
-        Err(e) => return Err(LLMError::RequestError(e)),
-    };
-
-    Ok(response_body.message.content)
+// Ollama takes the text of a message as a single string and its images in a separate list
+fn build_ollama_messages(messages: &[Message]) -> Vec<OllamaMessage> {
+    messages
+        .iter()
+        .map(|msg| {
+            let role = match msg.role {
+                Role::User => "user",
+                Role::Assistant => "assistant",
+                Role::System => "system",
+            };
+            match &msg.content {
+                MessageContent::Text(text) => OllamaMessage {
+                    role,
+                    content: text.clone(),
+                    images: Vec::new(),
+                },
+                MessageContent::MultiContent(blocks) => {
+                    let mut texts = Vec::new();
+                    let mut images = Vec::new();
+                    for block in blocks {
+                        match block {
+                            ContentBlock::Text { text } => texts.push(text.as_str()),
+                            ContentBlock::Image { source } => images.push(source.data.clone()),
+                            ContentBlock::ToolResult { content, .. } => {
+                                texts.push(content.as_str())
+                            }
+                            ContentBlock::ToolUse { .. }
+                            | ContentBlock::Thinking { .. }
+                            | ContentBlock::RedactedThinking { .. } => (),
+                        }
+                    }
+                    OllamaMessage {
+                        role,
+                        content: texts.join("\n"),
+                        images,
+                    }
+                }
+            }
+        })
+        .collect()
 }
@@ -0,0 +1,183 @@
+use crate::http_client;
+use crate::llm::LLMError;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{OnceLock, RwLock};
+
+// where and how to reach a provider's API, e.g. through a company gateway; keyed by provider
+// name, with "openai" also covering the embedding and transcription endpoints
+#[derive(Debug, Clone, Default)]
+pub struct EndpointConfig {
+    // replaces the provider's public base URL, e.g. "https://api.openai.com/v1"
+    pub base_url: Option<String>,
+    // replaces the provider's API key environment variable
+    pub api_key: Option<String>,
+    // sent with every request, e.g. gateway credentials or routing headers
+    pub headers: Vec<(String, String)>,
+    // OpenAI-compatible services only
+    pub azure: Option<AzureConfig>,
+}
+
+// Azure OpenAI addresses deployments rather than models and authenticates with an `api-key`
+// header: {base_url}/openai/deployments/{deployment}/{path}?api-version={api_version}
+#[derive(Debug, Clone, Default)]
+pub struct AzureConfig {
+    pub api_version: String,
+    // model name to deployment name, models without an entry use their name as the deployment
+    pub deployments: HashMap<String, String>,
+}
+
+static ENDPOINTS: OnceLock<RwLock<HashMap<String, EndpointConfig>>> = OnceLock::new();
+
+fn endpoints() -> &'static RwLock<HashMap<String, EndpointConfig>> {
+    ENDPOINTS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+pub fn configure_endpoint(provider: impl Into<String>, config: EndpointConfig) {
+    endpoints()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(provider.into(), config);
+}
+
+// the configured endpoint, or else the one described by the environment:
+// `{PROVIDER}_BASE_URL` and `{PROVIDER}_EXTRA_HEADERS` ("name: value" pairs separated by ";"),
+// plus `AZURE_OPENAI_ENDPOINT`, `AZURE_OPENAI_API_KEY`, `OPENAI_API_VERSION` and
+// `AZURE_OPENAI_DEPLOYMENTS` ("model=deployment" pairs separated by ",") for OpenAI
+pub fn endpoint_config(provider: &str) -> EndpointConfig {
+    if let Some(config) = endpoints()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(provider)
+    {
+        return config.clone();
+    }
+    let prefix = provider.to_uppercase();
+    let mut config = EndpointConfig {
+        base_url: env::var(format!("{prefix}_BASE_URL")).ok(),
+        api_key: None,
+        headers: env::var(format!("{prefix}_EXTRA_HEADERS"))
+            .map(|headers| parse_header_list(&headers))
+            .unwrap_or_default(),
+        azure: None,
+    };
+    if provider == "openai" {
+        if let Ok(azure_endpoint) = env::var("AZURE_OPENAI_ENDPOINT") {
+            config.base_url = Some(azure_endpoint);
+            config.api_key = env::var("AZURE_OPENAI_API_KEY").ok();
+            config.azure = Some(AzureConfig {
+                api_version: env::var("OPENAI_API_VERSION")
+                    .unwrap_or_else(|_| "2024-10-21".to_string()),
+                deployments: env::var("AZURE_OPENAI_DEPLOYMENTS")
+                    .map(|deployments| parse_deployments(&deployments))
+                    .unwrap_or_default(),
+            });
+        }
+    }
+    config
+}
+
+fn parse_header_list(headers: &str) -> Vec<(String, String)> {
+    headers
+        .split(';')
+        .filter_map(|header| header.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+fn parse_deployments(deployments: &str) -> HashMap<String, String> {
+    deployments
+        .split(',')
+        .filter_map(|deployment| deployment.split_once('='))
+        .map(|(model, deployment)| (model.trim().to_string(), deployment.trim().to_string()))
+        .collect()
+}
+
+impl EndpointConfig {
+    // a per-request `server_endpoint` takes precedence over the configured base URL
+    pub(crate) fn base_url<'a>(
+        &'a self,
+        server_endpoint: Option<&'a str>,
+        default: &'a str,
+    ) -> &'a str {
+        server_endpoint
+            .or(self.base_url.as_deref())
+            .unwrap_or(default)
+            .trim_end_matches('/')
+    }
+
+    pub(crate) fn api_key(&self, env_var: &str) -> Result<String, LLMError> {
+        if let Some(api_key) = &self.api_key {
+            return Ok(api_key.clone());
+        }
+        match env::var(env_var) {
+            Ok(key) => Ok(key),
+            // replayed sessions never reach the APIs, but the requests are still built with a key
+            Err(_) if http_client::is_replaying() => Ok("replay".to_string()),
+            Err(_) => Err(LLMError::RequestBuildingError(format!(
+                "{} environment variable not set",
+                env_var
+            ))),
+        }
+    }
+
+    pub(crate) fn add_headers(&self, headers: &mut HeaderMap) -> Result<(), LLMError> {
+        for (name, value) in &self.headers {
+            let name = match HeaderName::from_bytes(name.as_bytes()) {
+                Ok(name) => name,
+                Err(e) => return Err(LLMError::RequestBuildingError(e.to_string())),
+            };
+            let value = match HeaderValue::from_str(value) {
+                Ok(value) => value,
+                Err(e) => return Err(LLMError::RequestBuildingError(e.to_string())),
+            };
+            headers.insert(name, value);
+        }
+        Ok(())
+    }
+}
+
+// the URL and headers of a request to an OpenAI-shaped API (chat, embeddings, transcriptions)
+// at `path`, e.g. "chat/completions", for either OpenAI itself or an Azure deployment
+pub(crate) fn openai_target(
+    model: &str,
+    path: &str,
+    server_endpoint: Option<&str>,
+) -> Result<(String, HeaderMap), LLMError> {
+    let config = endpoint_config("openai");
+    let api_key = match &config.azure {
+        Some(_) => config.api_key("AZURE_OPENAI_API_KEY")?,
+        None => config.api_key("OPENAI_API_KEY")?,
+    };
+    let base_url = config.base_url(server_endpoint, "https://api.openai.com/v1");
+    let mut headers = HeaderMap::new();
+    let url = match &config.azure {
+        Some(azure) => {
+            let deployment = azure
+                .deployments
+                .get(model)
+                .map(String::as_str)
+                .unwrap_or(model);
+            headers.insert("api-key", header_value(&api_key)?);
+            format!(
+                "{base_url}/openai/deployments/{deployment}/{path}?api-version={}",
+                azure.api_version
+            )
+        }
+        None => {
+            headers.insert(AUTHORIZATION, header_value(&format!("Bearer {api_key}"))?);
+            format!("{base_url}/{path}")
+        }
+    };
+    config.add_headers(&mut headers)?;
+    Ok((url, headers))
+}
+
+pub(crate) fn header_value(value: &str) -> Result<HeaderValue, LLMError> {
+    match HeaderValue::from_str(value) {
+        Ok(value) => Ok(value),
+        Err(e) => Err(LLMError::RequestBuildingError(e.to_string())),
+    }
+}
@@ -0,0 +1,93 @@
+use crate::http_client::retry_after_from_headers;
+use crate::llm::{LLMError, ProviderError};
+use serde_json::Value;
+
+// reads the error body of a non-2xx response
+pub(crate) async fn from_response(provider: &str, response: reqwest::Response) -> LLMError {
+    let status = response.status().as_u16();
+    let retry_after = retry_after_from_headers(response.headers());
+    let body = response
+        .text()
+        .await
+        .unwrap_or_else(|_| "Unable to read error response".to_string());
+    let (code, message) = parse_body(&body);
+    classify(ProviderError {
+        provider: provider.to_string(),
+        status,
+        code,
+        message,
+        retry_after,
+    })
+}
+
+// the providers nest the error differently:
+// Anthropic: {"type": "error", "error": {"type": "overloaded_error", "message": "..."}}
+// OpenAI and Fireworks: {"error": {"message": "...", "type": "...", "code": "..."}}
+// Gemini: {"error": {"code": 429, "message": "...", "status": "RESOURCE_EXHAUSTED"}}
+// Ollama: {"error": "..."}
+fn parse_body(body: &str) -> (Option<String>, String) {
+    let value: Value = match serde_json::from_str(body) {
+        Ok(value) => value,
+        Err(_) => return (None, body.to_string()),
+    };
+    let error = match value.get("error") {
+        Some(Value::String(message)) => return (None, message.clone()),
+        Some(error) => error,
+        None => return (None, body.to_string()),
+    };
+    let code = ["code", "status", "type"]
+        .into_iter()
+        .find_map(|field| error.get(field).and_then(Value::as_str))
+        .map(str::to_string);
+    let message = match error.get("message").and_then(Value::as_str) {
+        Some(message) => message.to_string(),
+        None => body.to_string(),
+    };
+    (code, message)
+}
+
+pub(crate) fn classify(error: ProviderError) -> LLMError {
+    let code = error.code.as_deref().unwrap_or_default();
+    let message = error.message.to_lowercase();
+    let status = error.status;
+    if matches!(code, "context_length_exceeded" | "request_too_large")
+        || status == 413
+        || [
+            "prompt is too long",
+            "maximum context length",
+            "context window",
+            "exceeds the maximum number of tokens",
+        ]
+        .iter()
+        .any(|pattern| message.contains(pattern))
+    {
+        LLMError::ContextLengthExceeded(error)
+    } else if matches!(code, "content_policy_violation" | "content_filter")
+        || message.contains("content management policy")
+    {
+        LLMError::ContentPolicy(error)
+    } else if matches!(status, 401 | 403)
+        || matches!(
+            code,
+            "authentication_error" | "permission_error" | "invalid_api_key" | "UNAUTHENTICATED"
+        )
+        || message.contains("api key not valid")
+    {
+        LLMError::Authentication(error)
+    } else if status == 402
+        || code == "insufficient_quota"
+        || message.contains("credit balance is too low")
+    {
+        LLMError::QuotaExceeded(error)
+    } else if status == 429 || matches!(code, "rate_limit_error" | "RESOURCE_EXHAUSTED") {
+        LLMError::RateLimited(error)
+    } else if matches!(status, 503 | 529) || matches!(code, "overloaded_error" | "UNAVAILABLE") {
+        LLMError::Overloaded(error)
+    } else if status == 408 || (500..600).contains(&status) {
+        LLMError::ServerError(error)
+    } else if (400..500).contains(&status) {
+        LLMError::InvalidRequest(error)
+    } else {
+        LLMError::ApiError(error)
+    }
+}
@@ -1,24 +1,48 @@
-use crate::llm::{CompletionOptions, LLMError, Message, MessageContent, Model};
+use crate::http_client::send_with_retries;
+use crate::llm::endpoint::{endpoint_config, header_value};
+use crate::llm::errors;
+use crate::llm::openai::{
+    build_openai_messages, build_openai_response_format, OpenAIMessage, OpenAIResponseFormat,
+};
+use crate::llm::provider::{LlmProvider, ProviderCapabilities, SystemMessages};
+use crate::llm::streaming::{completion_stream, parse_json_event, sse_events};
+use crate::llm::{
+    Completion, CompletionChunk, CompletionOptions, CompletionStream, ContentBlock, LLMError,
+    Message, Model, Usage,
+};
+use async_trait::async_trait;
 use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
-use std::env;
 
-const FIREWORKS_API_URL: &str = "https://api.fireworks.ai/inference/v1/chat/completions";
+const FIREWORKS_API_BASE_URL: &str = "https://api.fireworks.ai/inference/v1";
 const FIREWORKS_MODEL_ENDPOINT_PREFIX: &str = "accounts/fireworks/models";
 
 #[derive(Serialize)]
-struct FireworksRequest<'a> {
+struct FireworksRequest {
     model: String,
-    messages: &'a [Message],
+    messages: Vec<OpenAIMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<OpenAIResponseFormat>,
 }
 
 #[derive(Deserialize)]
 struct FireworksResponse {
     choices: Vec<FireworksChoice>,
+    usage: Option<FireworksUsage>,
 }
 
 #[derive(Deserialize)]
@@ -31,79 +55,190 @@ struct FireworksMessage {
     content: String,
 }
 
+#[derive(Deserialize)]
+struct FireworksStreamResponse {
+    #[serde(default)]
+    choices: Vec<FireworksStreamChoice>,
+    usage: Option<FireworksUsage>,
+}
+
+#[derive(Deserialize)]
+struct FireworksStreamChoice {
+    #[serde(default)]
+    index: usize,
+    delta: FireworksDelta,
+}
+
+#[derive(Deserialize)]
+struct FireworksDelta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FireworksUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+pub struct FireworksProvider;
+
+#[async_trait]
+impl LlmProvider for FireworksProvider {
+    async fn complete(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, LLMError> {
+        completion_fireworks(model.clone(), messages, Some(options)).await
+    }
+
+    async fn stream(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<CompletionStream, LLMError> {
+        stream_fireworks(model.clone(), messages, Some(options)).await
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities {
+            images: true,
+            streaming: true,
+            tools: false,
+            candidates: true,
+            system_messages: SystemMessages::Anywhere,
+            alternating_roles: false,
+            user_first: false,
+            trailing_assistant: true,
+        }
+    }
+}
+
 pub async fn completion_fireworks(
     model: Model,
     messages: &[Message],
     options: Option<&CompletionOptions>,
-) -> Result<String, LLMError> {
-    // Check for images
-    if messages
-        .iter()
-        .any(|msg| matches!(msg.content, MessageContent::MultiContent(_)))
-    {
-        return Err(LLMError::ImagesNotSupported);
-    }
+) -> Result<Completion, LLMError> {
+    let response = send_fireworks_request(model, messages, options, false).await?;
+    let response_body: FireworksResponse = match response.json().await {
+        Ok(body) => body,
+        Err(e) => return Err(LLMError::RequestError(e)),
+    };
+
+    let usage = response_body
+        .usage
+        .map(|usage| Usage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+            ..Default::default()
+        })
+        .unwrap_or_default();
+    let mut candidates = response_body.choices.into_iter().map(|choice| {
+        vec![ContentBlock::Text {
+            text: choice.message.content,
+        }]
+    });
+    let content = match candidates.next() {
+        Some(content) => content,
+        None => return Err(LLMError::EmptyResponse),
+    };
+    Ok(Completion {
+        content,
+        alternatives: candidates.collect(),
+        usage,
+    })
+}
+
+pub async fn stream_fireworks(
+    model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+) -> Result<CompletionStream, LLMError> {
+    let response = send_fireworks_request(model, messages, options, true).await?;
+    Ok(completion_stream(sse_events(response), (), |_, event| {
+        if event.data == "[DONE]" {
+            return Ok(Vec::new());
+        }
+        let chunk: FireworksStreamResponse = parse_json_event(&event.data)?;
+        let mut chunks: Vec<CompletionChunk> = chunk
+            .choices
+            .into_iter()
+            // only the first candidate is streamed
+            .filter(|choice| choice.index == 0)
+            .filter_map(|choice| choice.delta.content)
+            .filter(|content| !content.is_empty())
+            .map(CompletionChunk::Delta)
+            .collect();
+        if let Some(usage) = chunk.usage {
+            chunks.push(CompletionChunk::Usage(Usage {
+                input_tokens: usage.prompt_tokens,
+                output_tokens: usage.completion_tokens,
+                ..Default::default()
+            }));
+        }
+        Ok(chunks)
+    }))
+}
 
+async fn send_fireworks_request(
+    model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+    stream: bool,
+) -> Result<reqwest::Response, LLMError> {
+    // images are sent as OpenAI-style `image_url` data URIs
     let req_body = FireworksRequest {
-        model: format!("{FIREWORKS_MODEL_ENDPOINT_PREFIX}/{model}"),
-        messages: messages,
-        temperature: options.and_then(|opt| (opt.temperature != 0.0).then_some(opt.temperature)),
+        // ids that are already a full path, e.g. of a fine-tuned model, are sent as they are
+        model: match model.to_string() {
+            model if model.contains('/') => model,
+            model => format!("{FIREWORKS_MODEL_ENDPOINT_PREFIX}/{model}"),
+        },
+        messages: build_openai_messages(messages),
+        temperature: options.and_then(|opt| opt.temperature),
+        top_p: options.and_then(|opt| opt.top_p),
+        stop: options.map(|opt| opt.stop.clone()).unwrap_or_default(),
+        seed: options.and_then(|opt| opt.seed),
+        n: options.and_then(|opt| opt.candidate_count),
         max_tokens: options
             .and_then(|opt| (opt.max_completion_tokens != 0).then_some(opt.max_completion_tokens)),
+        stream: stream.then_some(true),
+        response_format: options
+            .and_then(|opt| opt.response_schema.as_ref())
+            .map(build_openai_response_format),
     };
 
-    let api_key = match env::var("FIREWORKS_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            return Err(LLMError::RequestBuildingError(
-                "FIREWORKS_API_KEY environment variable not set".to_string(),
-            ))
-        }
-    };
+    let endpoint = endpoint_config("fireworks");
+    let api_key = endpoint.api_key("FIREWORKS_API_KEY")?;
+    let url = format!(
+        "{}/chat/completions",
+        endpoint.base_url(
+            options.and_then(|opt| opt.server_endpoint.as_deref()),
+            FIREWORKS_API_BASE_URL
+        )
+    );
 
     let mut headers = HeaderMap::new();
-    let auth_header = match HeaderValue::from_str(&format!("Bearer {api_key}")) {
-        Ok(header) => header,
-        Err(e) => return Err(LLMError::RequestBuildingError(e.to_string())),
-    };
-    headers.insert(AUTHORIZATION, auth_header);
+    headers.insert(AUTHORIZATION, header_value(&format!("Bearer {api_key}"))?);
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
-
-    let client = reqwest::Client::new();
-    let response = match client
-        .post(FIREWORKS_API_URL)
-        .headers(headers)
-        .json(&req_body)
-        .send()
-        .await
+    let accept = match stream {
+        true => "text/event-stream",
+        false => "application/json",
+    };
+    headers.insert(ACCEPT, HeaderValue::from_static(accept));
+    endpoint.add_headers(&mut headers)?;
+
+    let response = match send_with_retries(|client| {
+        client.post(&url).headers(headers.clone()).json(&req_body)
+    })
+    .await
     {
         Ok(resp) => resp,
         Err(e) => return Err(LLMError::RequestError(e)),
     };
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unable to read error response".to_string());
-        return Err(LLMError::Other(
-            format!(
-                "Fireworks API request failed with status {}: {}",
-                status, error_text
-            )
-            .into(),
-        ));
+    if !response.status().is_success() {
+        return Err(errors::from_response("Fireworks", response).await);
     }
-
-    let response_body: FireworksResponse = match response.json().await {
-        Ok(body) => body,
-        Err(e) => return Err(LLMError::RequestError(e)),
-    };
-
-    response_body
-        .choices
-        .first()
-        .map(|choice| choice.message.content.clone())
-        .ok_or(LLMError::EmptyResponse)
+    Ok(response)
 }
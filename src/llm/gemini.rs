@@ -1,13 +1,65 @@
-use crate::llm::{CompletionOptions, LLMError, Message, MessageContent, Model, Role};
+use crate::http_client::send_with_retries;
+use crate::llm::endpoint::{endpoint_config, header_value};
+use crate::llm::errors;
+use crate::llm::provider::{LlmProvider, ProviderCapabilities, SystemMessages};
+use crate::llm::streaming::{completion_stream, parse_json_event, sse_events};
+use crate::llm::{
+    Completion, CompletionChunk, CompletionOptions, CompletionStream, ContentBlock, LLMError,
+    Message, MessageContent, Model, Role, Tool, Usage,
+};
+use async_trait::async_trait;
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::collections::HashMap;
 
-const GEMINI_API_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+const GEMINI_API_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
 
 #[derive(Serialize)]
-struct GeminiPart {
-    text: String,
+#[serde(untagged)]
+enum GeminiPart {
+    Text {
+        text: String,
+    },
+    InlineData {
+        inline_data: GeminiInlineData,
+    },
+    FunctionCall {
+        function_call: GeminiFunctionCall,
+    },
+    FunctionResponse {
+        function_response: GeminiFunctionResponse,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct GeminiFunctionCall {
+    name: String,
+    #[serde(default)]
+    args: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct GeminiFunctionResponse {
+    name: String,
+    response: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct GeminiTool {
+    function_declarations: Vec<GeminiFunctionDeclaration>,
+}
+
+#[derive(Serialize)]
+struct GeminiFunctionDeclaration {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct GeminiInlineData {
+    mime_type: String,
+    data: String,
 }
 
 #[derive(Serialize)]
@@ -21,6 +73,26 @@ struct GeminiContent {
 struct GeminiGenerationConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f64>,
+    #[serde(rename = "topP")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(rename = "stopSequences")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(rename = "candidateCount")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidate_count: Option<u32>,
+    #[serde(rename = "maxOutputTokens")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<i32>,
+    #[serde(rename = "responseMimeType")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+    #[serde(rename = "responseSchema")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<serde_json::Value>,
 }
 
 #[derive(Serialize)]
@@ -36,11 +108,15 @@ struct GeminiRequest {
     generation_config: Option<GeminiGenerationConfig>,
     #[serde(skip_serializing_if = "Option::is_none")]
     system_instruction: Option<GeminiSystemInstruction>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<GeminiTool>,
 }
 
 #[derive(Deserialize)]
 struct GeminiResponse {
     candidates: Vec<GeminiCandidate>,
+    #[serde(rename = "usageMetadata")]
+    usage_metadata: Option<GeminiUsageMetadata>,
 }
 
 #[derive(Deserialize)]
@@ -55,35 +131,401 @@ struct GeminiResponseContent {
 
 #[derive(Deserialize)]
 struct GeminiResponsePart {
-    text: String,
+    text: Option<String>,
+    #[serde(rename = "functionCall")]
+    function_call: Option<GeminiFunctionCall>,
+}
+
+#[derive(Deserialize)]
+struct GeminiStreamResponse {
+    #[serde(default)]
+    candidates: Vec<GeminiStreamCandidate>,
+    #[serde(rename = "usageMetadata")]
+    usage_metadata: Option<GeminiUsageMetadata>,
+}
+
+#[derive(Deserialize)]
+struct GeminiStreamCandidate {
+    #[serde(default)]
+    index: usize,
+    content: Option<GeminiStreamContent>,
+    #[serde(rename = "finishReason")]
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GeminiStreamContent {
+    #[serde(default)]
+    parts: Vec<GeminiStreamPart>,
+}
+
+#[derive(Deserialize)]
+struct GeminiStreamPart {
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GeminiUsageMetadata {
+    #[serde(rename = "promptTokenCount", default)]
+    prompt_token_count: u32,
+    #[serde(rename = "candidatesTokenCount", default)]
+    candidates_token_count: u32,
+}
+
+pub struct GeminiProvider;
+
+#[async_trait]
+impl LlmProvider for GeminiProvider {
+    async fn complete(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, LLMError> {
+        completion_gemini(model.clone(), messages, Some(options)).await
+    }
+
+    async fn stream(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<CompletionStream, LLMError> {
+        stream_gemini(model.clone(), messages, Some(options)).await
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities {
+            images: true,
+            streaming: true,
+            tools: true,
+            candidates: true,
+            system_messages: SystemMessages::Leading,
+            alternating_roles: true,
+            user_first: true,
+            trailing_assistant: false,
+        }
+    }
 }
 
 pub(crate) async fn completion_gemini(
     model: Model,
     messages: &[Message],
     options: Option<&CompletionOptions>,
-) -> Result<String, LLMError> {
-    let mut contents = Vec::new();
-    let mut generation_config = None;
-    let mut system_content = None;
+) -> Result<Completion, LLMError> {
+    let response = send_gemini_request(model, messages, options, false).await?;
+    let response_body: GeminiResponse = match response.json().await {
+        Ok(body) => body,
+        Err(e) => return Err(LLMError::RequestError(e)),
+    };
 
-    if messages
-        .iter()
-        .any(|msg| matches!(msg.content, MessageContent::MultiContent(_)))
+    let usage_metadata = response_body.usage_metadata;
+    let mut candidates = response_body
+        .candidates
+        .into_iter()
+        .map(|candidate| build_content(candidate.content.parts));
+    let content = match candidates.next() {
+        Some(content) if !content.is_empty() => content,
+        _ => return Err(LLMError::EmptyResponse),
+    };
+    let alternatives = candidates.collect();
+    let usage = usage_metadata
+        .map(|usage| Usage {
+            input_tokens: usage.prompt_token_count,
+            output_tokens: usage.candidates_token_count,
+            ..Default::default()
+        })
+        .unwrap_or_default();
+    Ok(Completion {
+        content,
+        alternatives,
+        usage,
+    })
+}
+
+fn build_content(parts: Vec<GeminiResponsePart>) -> Vec<ContentBlock> {
+    let mut content = Vec::new();
+    for (idx, part) in parts.into_iter().enumerate() {
+        if let Some(text) = part.text {
+            content.push(ContentBlock::Text { text });
+        }
+        // Gemini does not assign ids to function calls, so one is made up from the position
+        if let Some(function_call) = part.function_call {
+            content.push(ContentBlock::ToolUse {
+                id: format!("{}-{}", function_call.name, idx),
+                name: function_call.name,
+                input: function_call.args,
+            });
+        }
+    }
+    content
+}
+
+pub(crate) async fn stream_gemini(
+    model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+) -> Result<CompletionStream, LLMError> {
+    let response = send_gemini_request(model, messages, options, true).await?;
+    Ok(completion_stream(sse_events(response), (), |_, event| {
+        let chunk: GeminiStreamResponse = parse_json_event(&event.data)?;
+        Ok(parse_gemini_stream_chunk(chunk))
+    }))
+}
+
+fn parse_gemini_stream_chunk(chunk: GeminiStreamResponse) -> Vec<CompletionChunk> {
+    let mut chunks = Vec::new();
+    let mut finished = false;
+    // only the first candidate is streamed
+    if let Some(candidate) = chunk
+        .candidates
+        .into_iter()
+        .find(|candidate| candidate.index == 0)
     {
-        return Err(LLMError::ImagesNotSupported);
+        finished = candidate.finish_reason.is_some();
+        if let Some(content) = candidate.content {
+            chunks.extend(
+                content
+                    .parts
+                    .into_iter()
+                    .filter_map(|part| part.text)
+                    .filter(|text| !text.is_empty())
+                    .map(CompletionChunk::Delta),
+            );
+        }
+    }
+    // every chunk carries the running usage, only report the final one
+    if finished {
+        if let Some(usage) = chunk.usage_metadata {
+            chunks.push(CompletionChunk::Usage(Usage {
+                input_tokens: usage.prompt_token_count,
+                output_tokens: usage.candidates_token_count,
+                ..Default::default()
+            }));
+        }
     }
+    chunks
+}
+
+// keeps text and images interleaved in the order they appear in the message
+fn build_gemini_parts(
+    content: &MessageContent,
+    tool_names: &HashMap<&str, &str>,
+) -> Vec<GeminiPart> {
+    match content {
+        MessageContent::Text(text) => vec![GeminiPart::Text { text: text.clone() }],
+        MessageContent::MultiContent(blocks) => blocks
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(GeminiPart::Text { text: text.clone() }),
+                ContentBlock::Image { source } => Some(GeminiPart::InlineData {
+                    inline_data: GeminiInlineData {
+                        mime_type: source.media_type.clone(),
+                        data: source.data.clone(),
+                    },
+                }),
+                ContentBlock::ToolUse { name, input, .. } => Some(GeminiPart::FunctionCall {
+                    function_call: GeminiFunctionCall {
+                        name: name.clone(),
+                        args: input.clone(),
+                    },
+                }),
+                ContentBlock::ToolResult {
+                    tool_use_id,
+                    content,
+                    is_error,
+                } => Some(GeminiPart::FunctionResponse {
+                    function_response: GeminiFunctionResponse {
+                        name: tool_names
+                            .get(tool_use_id.as_str())
+                            .unwrap_or(&tool_use_id.as_str())
+                            .to_string(),
+                        response: if *is_error {
+                            serde_json::json!({ "error": content })
+                        } else {
+                            serde_json::json!({ "content": content })
+                        },
+                    },
+                }),
+                ContentBlock::Thinking { .. } | ContentBlock::RedactedThinking { .. } => None,
+            })
+            .collect(),
+    }
+}
+
+fn build_gemini_tools(tools: &[Tool]) -> Vec<GeminiTool> {
+    if tools.is_empty() {
+        return Vec::new();
+    }
+    vec![GeminiTool {
+        function_declarations: tools
+            .iter()
+            .map(|tool| GeminiFunctionDeclaration {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                parameters: tool.input_schema.clone(),
+            })
+            .collect(),
+    }]
+}
+
+// Gemini accepts only a subset of JSON schema (the OpenAPI schema object), so the keywords it
+// rejects are dropped, nullable types are rewritten and the `$ref`s to `definitions` are inlined
+fn build_gemini_schema(schema: &serde_json::Value) -> serde_json::Value {
+    let definitions = match schema.get("definitions") {
+        Some(serde_json::Value::Object(definitions)) => definitions.clone(),
+        _ => serde_json::Map::new(),
+    };
+    gemini_schema(schema, &definitions, &mut Vec::new())
+}
+
+// `expanding` holds the definitions being inlined, so a recursive type ends in a plain object
+fn gemini_schema(
+    schema: &serde_json::Value,
+    definitions: &serde_json::Map<String, serde_json::Value>,
+    expanding: &mut Vec<String>,
+) -> serde_json::Value {
+    match schema {
+        serde_json::Value::Object(object) => {
+            let mut gemini_schema_object = serde_json::Map::new();
+            if let Some(reference) = object.get("$ref").and_then(|value| value.as_str()) {
+                let name = reference.trim_start_matches("#/definitions/").to_string();
+                match definitions.get(&name) {
+                    Some(definition) if !expanding.contains(&name) => {
+                        expanding.push(name);
+                        if let serde_json::Value::Object(inlined) =
+                            gemini_schema(definition, definitions, expanding)
+                        {
+                            gemini_schema_object = inlined;
+                        }
+                        expanding.pop();
+                    }
+                    _ => {
+                        gemini_schema_object
+                            .insert("type".to_string(), serde_json::Value::from("object"));
+                    }
+                }
+            }
+            for (key, value) in object {
+                match key.as_str() {
+                    "$schema" | "$ref" | "title" | "definitions" | "additionalProperties" => {}
+                    "type" => match value {
+                        serde_json::Value::Array(types) => {
+                            let non_null: Vec<_> = types
+                                .iter()
+                                .filter(|t| t.as_str() != Some("null"))
+                                .collect();
+                            if let Some(t) = non_null.first() {
+                                gemini_schema_object.insert(key.clone(), (*t).clone());
+                            }
+                            if non_null.len() < types.len() {
+                                gemini_schema_object
+                                    .insert("nullable".to_string(), serde_json::Value::Bool(true));
+                            }
+                        }
+                        _ => {
+                            gemini_schema_object.insert(key.clone(), value.clone());
+                        }
+                    },
+                    // e.g. `Option<T>` of a struct, `{"anyOf": [{"$ref": ...}, {"type": "null"}]}`
+                    "anyOf" | "allOf" if single_schema(value).is_some() => {
+                        if let Some((single, nullable)) = single_schema(value) {
+                            if let serde_json::Value::Object(single) =
+                                gemini_schema(single, definitions, expanding)
+                            {
+                                gemini_schema_object.extend(single);
+                            }
+                            if nullable {
+                                gemini_schema_object
+                                    .insert("nullable".to_string(), serde_json::Value::Bool(true));
+                            }
+                        }
+                    }
+                    "properties" => {
+                        let properties = match value {
+                            serde_json::Value::Object(properties) => properties
+                                .iter()
+                                .map(|(name, property)| {
+                                    (
+                                        name.clone(),
+                                        gemini_schema(property, definitions, expanding),
+                                    )
+                                })
+                                .collect(),
+                            _ => serde_json::Map::new(),
+                        };
+                        gemini_schema_object
+                            .insert(key.clone(), serde_json::Value::Object(properties));
+                    }
+                    _ => {
+                        gemini_schema_object
+                            .insert(key.clone(), gemini_schema(value, definitions, expanding));
+                    }
+                }
+            }
+            serde_json::Value::Object(gemini_schema_object)
+        }
+        serde_json::Value::Array(values) => serde_json::Value::Array(
+            values
+                .iter()
+                .map(|value| gemini_schema(value, definitions, expanding))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+// the only schema of a list that has one besides `{"type": "null"}`, and whether it had that
+fn single_schema(schemas: &serde_json::Value) -> Option<(&serde_json::Value, bool)> {
+    let schemas = schemas.as_array()?;
+    let non_null: Vec<&serde_json::Value> = schemas
+        .iter()
+        .filter(|schema| schema.get("type").and_then(|t| t.as_str()) != Some("null"))
+        .collect();
+    match non_null[..] {
+        [single] => Some((single, non_null.len() < schemas.len())),
+        _ => None,
+    }
+}
+
+async fn send_gemini_request(
+    model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+    stream: bool,
+) -> Result<reqwest::Response, LLMError> {
+    let mut contents = Vec::new();
+    let mut system_content = None;
+    // function responses are matched to their calls by name rather than by id
+    let tool_names: HashMap<&str, &str> = messages
+        .iter()
+        .filter_map(|msg| match &msg.content {
+            MessageContent::MultiContent(blocks) => Some(blocks),
+            MessageContent::Text(_) => None,
+        })
+        .flatten()
+        .filter_map(|block| match block {
+            ContentBlock::ToolUse { id, name, .. } => Some((id.as_str(), name.as_str())),
+            _ => None,
+        })
+        .collect();
 
     for msg in messages {
         match msg.role {
             Role::System => {
-                generation_config = Some(GeminiGenerationConfig {
-                    temperature: options
-                        .and_then(|opt| (opt.temperature != 0.0).then_some(opt.temperature)),
-                });
                 system_content = match &msg.content {
                     MessageContent::Text(text) => Some(text.clone()),
-                    MessageContent::MultiContent(_) => unreachable!(),
+                    // system instructions should be text only
+                    MessageContent::MultiContent(blocks) => Some(
+                        blocks
+                            .iter()
+                            .filter_map(|block| match block {
+                                ContentBlock::Text { text } => Some(text.as_str()),
+                                _ => None,
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n"),
+                    ),
                 };
             }
             Role::User | Role::Assistant => {
@@ -93,12 +535,7 @@ pub(crate) async fn completion_gemini(
                     _ => None,
                 };
                 contents.push(GeminiContent {
-                    parts: vec![GeminiPart {
-                        text: match &msg.content {
-                            MessageContent::Text(text) => text.clone(),
-                            MessageContent::MultiContent(_) => unreachable!(), // We checked earlier
-                        },
-                    }],
+                    parts: build_gemini_parts(&msg.content, &tool_names),
                     role,
                 });
             }
@@ -106,64 +543,97 @@ pub(crate) async fn completion_gemini(
     }
 
     let system_content = system_content.map(|content| GeminiSystemInstruction {
-        parts: GeminiPart { text: content },
+        parts: GeminiPart::Text { text: content },
+    });
+
+    let response_schema = options.and_then(|opt| opt.response_schema.as_ref());
+    let generation_config = options.map(|opt| GeminiGenerationConfig {
+        temperature: opt.temperature,
+        top_p: opt.top_p,
+        stop_sequences: opt.stop.clone(),
+        seed: opt.seed,
+        candidate_count: opt.candidate_count,
+        max_output_tokens: (opt.max_completion_tokens != 0).then_some(opt.max_completion_tokens),
+        response_mime_type: response_schema.map(|_| "application/json"),
+        response_schema: response_schema.map(|schema| build_gemini_schema(&schema.schema)),
     });
 
     let req_body = GeminiRequest {
         contents,
         generation_config,
         system_instruction: system_content,
+        tools: options
+            .map(|opt| build_gemini_tools(&opt.tools))
+            .unwrap_or_default(),
     };
 
-    let api_key = match env::var("GOOGLE_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            return Err(LLMError::RequestBuildingError(
-                "GOOGLE_API_KEY environment variable not set".to_string(),
-            ))
-        }
-    };
+    let endpoint = endpoint_config("google");
+    let api_key = endpoint.api_key("GOOGLE_API_KEY")?;
 
+    // the key goes in a header rather than the query string so it stays out of gateway logs
     let mut headers = HeaderMap::new();
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert("x-goog-api-key", header_value(&api_key)?);
+    endpoint.add_headers(&mut headers)?;
 
-    let url = format!("{GEMINI_API_URL}/{model}:generateContent?key={api_key}");
+    let base_url = endpoint.base_url(
+        options.and_then(|opt| opt.server_endpoint.as_deref()),
+        GEMINI_API_BASE_URL,
+    );
+    let url = if stream {
+        format!("{base_url}/models/{model}:streamGenerateContent?alt=sse")
+    } else {
+        format!("{base_url}/models/{model}:generateContent")
+    };
 
-    let client = reqwest::Client::new();
-    let response = match client
-        .post(url)
-        .headers(headers)
-        .json(&req_body)
-        .send()
-        .await
+    let response = match send_with_retries(|client| {
+        client.post(&url).headers(headers.clone()).json(&req_body)
+    })
+    .await
     {
         Ok(resp) => resp,
         Err(e) => return Err(LLMError::RequestError(e)),
     };
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unable to read error response".to_string());
-        return Err(LLMError::Other(
-            format!(
-                "Gemini API request failed with status {}: {}",
-                status, error_text
-            )
-            .into(),
-        ));
+    if !response.status().is_success() {
+        return Err(errors::from_response("Gemini", response).await);
     }
+    Ok(response)
+}
 
-    let response_body: GeminiResponse = match response.json().await {
-        Ok(body) => body,
-        Err(e) => return Err(LLMError::RequestError(e)),
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ResponseSchema;
+    use schemars::JsonSchema;
 
-    response_body
-        .candidates
-        .first()
-        .and_then(|candidate| candidate.content.parts.first())
-        .map(|part| part.text.clone())
-        .ok_or(LLMError::EmptyResponse)
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct Screen {
+        title: String,
+        window: Window,
+        previous: Option<Window>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema)]
+    struct Window {
+        app: String,
+        width: u32,
+    }
+
+    #[test]
+    fn inlines_nested_types() {
+        let schema = build_gemini_schema(&ResponseSchema::for_type::<Screen>().schema);
+        let text = schema.to_string();
+        assert!(!text.contains("$ref"));
+        assert!(!text.contains("definitions"));
+        let window = &schema["properties"]["window"];
+        assert_eq!(window["type"], "object");
+        assert_eq!(window["properties"]["app"]["type"], "string");
+        assert_eq!(window["required"], serde_json::json!(["app", "width"]));
+        let previous = &schema["properties"]["previous"];
+        assert_eq!(previous["properties"]["width"]["type"], "integer");
+        assert_eq!(previous["nullable"], true);
+        assert!(previous.get("anyOf").is_none());
+    }
 }
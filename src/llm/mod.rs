@@ -1,13 +1,39 @@
+use crate::ledger::{self, Purpose};
 use crate::prompts::Prompt;
+use crate::utils::parse_markdown_code_block;
+use catalog::{model_info, ModelInfo};
+use context::{reduce_context, CONTEXT_REDUCTIONS};
+use futures::stream::{BoxStream, StreamExt};
+use normalize::normalize_messages;
+use provider::{get_provider, LlmProvider};
+use scheduler::{CancelToken, Priority};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
 pub mod anthropic;
+pub mod cache;
+pub mod catalog;
+mod context;
 pub mod custom;
+pub mod endpoint;
+mod errors;
 pub mod fireworks;
 pub mod gemini;
+mod normalize;
 pub mod openai;
+pub mod pricing;
+pub mod provider;
+pub mod scheduler;
+mod streaming;
+pub mod tokens;
+pub mod tools;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Role {
@@ -33,6 +59,51 @@ pub enum ContentBlock {
     Text { text: String },
     #[serde(rename = "image")]
     Image { source: ImageSource },
+    // a call the assistant wants to make, answered by a `ToolResult` in the next user message
+    #[serde(rename = "tool_use")]
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    #[serde(rename = "tool_result")]
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        is_error: bool,
+    },
+    // Anthropic's extended thinking, which has to be sent back unchanged with the tool results
+    // that follow it; the other providers leave it out
+    #[serde(rename = "thinking")]
+    Thinking { thinking: String, signature: String },
+    #[serde(rename = "redacted_thinking")]
+    RedactedThinking { data: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    // JSON schema of the tool input
+    pub input_schema: serde_json::Value,
+}
+
+// the JSON schema that the response must follow
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseSchema {
+    pub name: String,
+    pub schema: serde_json::Value,
+}
+
+impl ResponseSchema {
+    pub fn for_type<T: JsonSchema>() -> Self {
+        let schema = schemars::schema_for!(T);
+        Self {
+            name: T::schema_name(),
+            schema: serde_json::to_value(schema).unwrap_or_default(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,10 +120,11 @@ pub struct Message {
     pub content: MessageContent,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum Provider {
     #[serde(rename = "openai")]
     OpenAI,
+    #[default]
     #[serde(rename = "anthropic")]
     Anthropic,
     #[serde(rename = "google")]
@@ -61,19 +133,40 @@ pub enum Provider {
     Fireworks,
     #[serde(rename = "custom")]
     Custom,
+    // an implementation added with `provider::register_provider`
+    #[serde(untagged)]
+    Registered(String),
+}
+
+impl Provider {
+    pub fn name(&self) -> &str {
+        match self {
+            Provider::OpenAI => "openai",
+            Provider::Anthropic => "anthropic",
+            Provider::Google => "google",
+            Provider::Fireworks => "fireworks",
+            Provider::Custom => "custom",
+            Provider::Registered(name) => name,
+        }
+    }
 }
 
-impl Default for Provider {
-    fn default() -> Self {
-        Provider::Anthropic
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum Model {
     GPT4o,
     GPT4oMini,
+    O1,
+    O1Mini,
+    O3Mini,
+    #[default]
     Claude35Sonnet,
+    Claude37Sonnet,
     Gemini2Flash,
     Gemini15Flash,
     Gemini15Flash8B,
@@ -85,11 +178,41 @@ pub enum Model {
     Llama32Instruct70B,
     Llama32Instruct405B,
     Custom,
+    // any other model id the provider accepts, described by `catalog::register_model`
+    Id(String),
 }
 
-impl Default for Model {
-    fn default() -> Self {
-        Model::Claude35Sonnet
+impl From<&str> for Model {
+    fn from(id: &str) -> Self {
+        let known = [
+            Model::GPT4o,
+            Model::GPT4oMini,
+            Model::O1,
+            Model::O1Mini,
+            Model::O3Mini,
+            Model::Claude35Sonnet,
+            Model::Claude37Sonnet,
+            Model::Gemini2Flash,
+            Model::Gemini15Flash,
+            Model::Gemini15Flash8B,
+            Model::Gemini15Pro,
+            Model::Llama32Instruct1B,
+            Model::Llama32Instruct3B,
+            Model::Llama31Instruct8B,
+            Model::Llama32Vision11B,
+            Model::Llama32Instruct70B,
+            Model::Llama32Instruct405B,
+        ];
+        known
+            .into_iter()
+            .find(|model| model.to_string() == id)
+            .unwrap_or_else(|| Model::Id(id.to_string()))
+    }
+}
+
+impl From<String> for Model {
+    fn from(id: String) -> Self {
+        Model::from(id.as_str())
     }
 }
 
@@ -98,7 +221,11 @@ impl fmt::Display for Model {
         match self {
             Model::GPT4o => write!(f, "gpt-4o"),
             Model::GPT4oMini => write!(f, "gpt-4o-mini"),
+            Model::O1 => write!(f, "o1"),
+            Model::O1Mini => write!(f, "o1-mini"),
+            Model::O3Mini => write!(f, "o3-mini"),
             Model::Claude35Sonnet => write!(f, "claude-3-5-sonnet-latest"),
+            Model::Claude37Sonnet => write!(f, "claude-3-7-sonnet-latest"),
             Model::Gemini2Flash => write!(f, "gemini-2.0-flash-exp"),
             Model::Gemini15Flash => write!(f, "gemini-1.5-flash"),
             Model::Gemini15Flash8B => write!(f, "gemini-1.5-flash-8b"),
@@ -107,9 +234,10 @@ impl fmt::Display for Model {
             Model::Llama32Instruct3B => write!(f, "llama-v3p2-3b-instruct"),
             Model::Llama31Instruct8B => write!(f, "llama-v3p1-8b-instruct"),
             Model::Llama32Vision11B => write!(f, "llama-v3p2-11b-vision-instruct"),
-            Model::Llama32Instruct70B => write!(f, "llama-v3p2-70b-instruct"),
-            Model::Llama32Instruct405B => write!(f, "llama-v3p2-405b-instruct"),
+            Model::Llama32Instruct70B => write!(f, "llama-v3p1-70b-instruct"),
+            Model::Llama32Instruct405B => write!(f, "llama-v3p1-405b-instruct"),
             Model::Custom => write!(f, "custom"),
+            Model::Id(id) => write!(f, "{}", id),
         }
     }
 }
@@ -120,10 +248,27 @@ pub struct CompletionBuilder {
     provider: Option<Provider>,
     messages: Vec<Message>,
     temperature: Option<f64>,
+    top_p: Option<f64>,
+    stop: Vec<String>,
+    seed: Option<u64>,
+    candidate_count: Option<u32>,
     max_completion_tokens: Option<i32>,
     server_endpoint: Option<String>,
     custom_server_endpoint: Option<String>,
     custom_model: Option<String>,
+    custom_dialect: Option<custom::CustomDialect>,
+    fallbacks: Vec<(Provider, Model)>,
+    tools: Vec<Tool>,
+    response_schema: Option<ResponseSchema>,
+    purpose: Option<Purpose>,
+    cache: bool,
+    cache_breakpoints: Vec<usize>,
+    thinking_budget: Option<u32>,
+    image_descriptions: HashMap<usize, String>,
+    image_drop_order: Vec<usize>,
+    reasoning_effort: Option<ReasoningEffort>,
+    prefill: bool,
+    cancel: Option<CancelToken>,
 }
 
 impl CompletionBuilder {
@@ -151,6 +296,29 @@ impl CompletionBuilder {
         self
     }
 
+    pub fn top_p(mut self, top_p: f64) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    // the completion ends before the first of these, which is not included
+    pub fn stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    // best-effort determinism, ignored by Anthropic
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    // how many completions to generate, the ones after the first are returned as `alternatives`
+    pub fn candidate_count(mut self, candidate_count: u32) -> Self {
+        self.candidate_count = Some(candidate_count);
+        self
+    }
+
     pub fn max_completion_tokens(mut self, tokens: i32) -> Self {
         self.max_completion_tokens = Some(tokens);
         self
@@ -171,6 +339,91 @@ impl CompletionBuilder {
         self
     }
 
+    pub fn custom_dialect(mut self, dialect: custom::CustomDialect) -> Self {
+        self.custom_dialect = Some(dialect);
+        self
+    }
+
+    // tried in order when the provider before it fails with a retryable error
+    pub fn fallback(mut self, provider: Provider, model: Model) -> Self {
+        self.fallbacks.push((provider, model));
+        self
+    }
+
+    pub fn fallbacks(mut self, fallbacks: Vec<(Provider, Model)>) -> Self {
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    pub fn tools(mut self, tools: Vec<Tool>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    pub fn response_schema(mut self, response_schema: ResponseSchema) -> Self {
+        self.response_schema = Some(response_schema);
+        self
+    }
+
+    pub fn purpose(mut self, purpose: Purpose) -> Self {
+        self.purpose = Some(purpose);
+        self
+    }
+
+    // reuses the stored completion for an identical earlier request; only requests at temperature
+    // 0.0 are cached, since the others are meant to vary
+    pub fn cache(mut self, enabled: bool) -> Self {
+        self.cache = enabled;
+        self
+    }
+
+    // indexes of the messages that end a prefix expected to be resent unchanged, at most four;
+    // Anthropic caches the prompt up to each of them and ignores the prefixes that are too short
+    pub fn cache_breakpoints(mut self, cache_breakpoints: Vec<usize>) -> Self {
+        self.cache_breakpoints = cache_breakpoints;
+        self
+    }
+
+    // lets the model reason for up to `budget_tokens` before answering, Anthropic only; the
+    // reasoning comes back as `ContentBlock::Thinking` or `CompletionChunk::ThinkingDelta`
+    pub fn thinking(mut self, budget_tokens: u32) -> Self {
+        self.thinking_budget = Some(budget_tokens);
+        self
+    }
+
+    // text standing in for the images of the message at each index when the request does not
+    // fit in the model's context even with fewer, downscaled images
+    pub fn image_descriptions(mut self, image_descriptions: HashMap<usize, String>) -> Self {
+        self.image_descriptions = image_descriptions;
+        self
+    }
+
+    // indexes of the messages whose images go first when the request does not fit in the model's
+    // context; the images of the other messages go after them, oldest first
+    pub fn image_drop_order(mut self, image_drop_order: Vec<usize>) -> Self {
+        self.image_drop_order = image_drop_order;
+        self
+    }
+
+    // how long OpenAI's reasoning models think before answering, ignored by the other models
+    pub fn reasoning_effort(mut self, reasoning_effort: ReasoningEffort) -> Self {
+        self.reasoning_effort = Some(reasoning_effort);
+        self
+    }
+
+    // the last message is the start of the assistant's answer, which the response continues
+    pub fn prefill(mut self, enabled: bool) -> Self {
+        self.prefill = enabled;
+        self
+    }
+
+    // stops the request while it waits for a slot or for the provider, with `LLMError::Cancelled`;
+    // a stream that has started is not stopped
+    pub fn cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
     pub fn build(self) -> CompletionRequest {
         let model = match self.model {
             Some(m) => m,
@@ -181,26 +434,151 @@ impl CompletionBuilder {
             None => Provider::Anthropic,
         };
         let options = CompletionOptions {
-            temperature: self.temperature.unwrap_or(0.0),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            stop: self.stop,
+            seed: self.seed,
+            candidate_count: self.candidate_count,
             max_completion_tokens: self.max_completion_tokens.unwrap_or(0),
             server_endpoint: self.server_endpoint,
             custom_server_endpoint: self.custom_server_endpoint,
             custom_model: self.custom_model,
+            custom_dialect: self.custom_dialect.unwrap_or_default(),
+            tools: self.tools,
+            response_schema: self.response_schema,
+            cache_breakpoints: self.cache_breakpoints,
+            thinking_budget: self.thinking_budget,
+            image_descriptions: self.image_descriptions,
+            image_drop_order: self.image_drop_order,
+            reasoning_effort: self.reasoning_effort,
+            prefill: self.prefill,
         };
         CompletionRequest {
             model,
             provider,
             messages: self.messages,
             options,
+            fallbacks: self.fallbacks,
+            purpose: self.purpose.unwrap_or_default(),
+            cache: self.cache,
+            cancel: self.cancel,
         }
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct CompletionRequest {
     pub model: Model,
     pub provider: Provider,
     pub messages: Vec<Message>,
     pub options: CompletionOptions,
+    pub fallbacks: Vec<(Provider, Model)>,
+    // the ledger category the usage is recorded under
+    pub purpose: Purpose,
+    // whether `do_request_full` goes through the on-disk cache
+    pub cache: bool,
+    pub cancel: Option<CancelToken>,
+}
+
+// what a provider returns for a single completion
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    pub content: Vec<ContentBlock>,
+    // the other candidates, when more than one was requested
+    pub alternatives: Vec<Vec<ContentBlock>>,
+    pub usage: Usage,
+}
+
+impl Completion {
+    pub fn text(&self) -> String {
+        content_text(&self.content)
+    }
+
+    pub fn thinking(&self) -> String {
+        content_thinking(&self.content)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompletionResponse {
+    pub content: Vec<ContentBlock>,
+    // the other candidates, when more than one was requested
+    pub alternatives: Vec<Vec<ContentBlock>>,
+    // the provider and model that produced the completion, which may be a fallback
+    pub provider: Provider,
+    pub model: Model,
+    pub usage: Usage,
+}
+
+impl CompletionResponse {
+    pub fn text(&self) -> String {
+        content_text(&self.content)
+    }
+
+    pub fn thinking(&self) -> String {
+        content_thinking(&self.content)
+    }
+
+    // the text of every candidate, starting with `content`
+    pub fn candidate_texts(&self) -> Vec<String> {
+        std::iter::once(&self.content)
+            .chain(&self.alternatives)
+            .map(|content| content_text(content))
+            .collect()
+    }
+
+    pub fn tool_uses(&self) -> Vec<(&str, &str, &serde_json::Value)> {
+        self.content
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::ToolUse { id, name, input } => {
+                    Some((id.as_str(), name.as_str(), input))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn content_text(content: &[ContentBlock]) -> String {
+    content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+fn content_thinking(content: &[ContentBlock]) -> String {
+    content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Thinking { thinking, .. } => Some(thinking.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+const STRUCTURED_OUTPUT_MAX_ATTEMPTS: usize = 3;
+
+// providers without a native JSON mode may still wrap the object in a markdown code block
+fn parse_structured_response<T: DeserializeOwned>(text: &str) -> Result<T, serde_json::Error> {
+    match serde_json::from_str(text.trim()) {
+        Ok(value) => Ok(value),
+        Err(e) => match parse_markdown_code_block(text) {
+            Ok(code_block) => serde_json::from_str(code_block.trim()),
+            Err(_) => Err(e),
+        },
+    }
+}
+
+pub struct CompletionStreamResponse {
+    pub stream: CompletionStream,
+    pub provider: Provider,
+    pub model: Model,
 }
 
 impl CompletionRequest {
@@ -215,21 +593,328 @@ impl CompletionRequest {
             provider,
             messages,
             options,
+            fallbacks: Vec::new(),
+            purpose: Purpose::default(),
+            cache: false,
+            cancel: None,
         }
     }
 
     pub async fn do_request(self) -> Result<String, LLMError> {
-        completion(self.model, self.provider, self.messages, self.options).await
+        self.do_request_full().await.map(|response| response.text())
+    }
+
+    pub async fn do_request_full(self) -> Result<CompletionResponse, LLMError> {
+        if !self.cache || self.options.temperature != Some(0.0) {
+            return self.do_request_uncached().await;
+        }
+        let candidates = self.candidates();
+        let key = cache::cache_key(&candidates, &self.messages, &self.options);
+        if let Some(cached) = cache::get(&key).await {
+            let (provider, model) = candidates
+                .into_iter()
+                .find(|(provider, model)| {
+                    *provider == cached.provider && model.to_string() == cached.model
+                })
+                .unwrap_or((self.provider, self.model));
+            return Ok(CompletionResponse {
+                content: cached.content,
+                alternatives: cached.alternatives,
+                provider,
+                model,
+                usage: cached.usage,
+            });
+        }
+        let response = self.do_request_uncached().await?;
+        cache::put(
+            &key,
+            &cache::CachedCompletion {
+                content: response.content.clone(),
+                alternatives: response.alternatives.clone(),
+                usage: response.usage,
+                provider: response.provider.clone(),
+                model: response.model.to_string(),
+            },
+        )
+        .await;
+        Ok(response)
+    }
+
+    async fn do_request_uncached(self) -> Result<CompletionResponse, LLMError> {
+        let mut errors = Vec::new();
+        for (provider, model) in self.candidates() {
+            let priority = Priority::for_purpose(self.purpose);
+            let request = async {
+                match &self.cancel {
+                    Some(cancel) => tokio::select! {
+                        result = self.complete_candidate(&provider, &model) => result,
+                        _ = cancel.cancelled() => Err(LLMError::Cancelled),
+                    },
+                    None => self.complete_candidate(&provider, &model).await,
+                }
+            };
+            // the slot is given back while the request waits to be retried
+            let result = match scheduler::with_slot(
+                provider.name(),
+                priority,
+                self.cancel.as_ref(),
+                request,
+            )
+            .await
+            {
+                Some((result, _permit)) => result,
+                None => return Err(LLMError::Cancelled),
+            };
+            match result {
+                Ok(completion) => {
+                    ledger::record_completion(
+                        self.purpose,
+                        &provider,
+                        &model,
+                        self.options.custom_model.as_deref(),
+                        completion.usage,
+                    );
+                    return Ok(CompletionResponse {
+                        content: completion.content,
+                        alternatives: completion.alternatives,
+                        provider,
+                        model,
+                        usage: completion.usage,
+                    });
+                }
+                Err(e) => {
+                    let can_fall_back = e.is_retryable() || e.is_provider_unavailable();
+                    errors.push(e);
+                    if !can_fall_back {
+                        break;
+                    }
+                }
+            }
+        }
+        Err(fallback_error(errors))
+    }
+
+    pub async fn do_request_stream(self) -> Result<CompletionStream, LLMError> {
+        self.do_request_stream_full()
+            .await
+            .map(|response| response.stream)
+    }
+
+    // falls back only while opening the stream, not once tokens have been sent
+    pub async fn do_request_stream_full(self) -> Result<CompletionStreamResponse, LLMError> {
+        let mut errors = Vec::new();
+        for (provider, model) in self.candidates() {
+            let priority = Priority::for_purpose(self.purpose);
+            let request = async {
+                match &self.cancel {
+                    Some(cancel) => tokio::select! {
+                        result = self.stream_candidate(&provider, &model) => result,
+                        _ = cancel.cancelled() => Err(LLMError::Cancelled),
+                    },
+                    None => self.stream_candidate(&provider, &model).await,
+                }
+            };
+            let (result, permit) = match scheduler::with_slot(
+                provider.name(),
+                priority,
+                self.cancel.as_ref(),
+                request,
+            )
+            .await
+            {
+                Some(admitted) => admitted,
+                None => return Err(LLMError::Cancelled),
+            };
+            match result {
+                Ok(stream) => {
+                    // the usage arrives at the end of the stream, and the slot is held until then
+                    let purpose = self.purpose;
+                    let custom_model = self.options.custom_model.clone();
+                    let (ledger_provider, ledger_model) = (provider.clone(), model.clone());
+                    let stream = stream
+                        .inspect(move |chunk| {
+                            let _permit = &permit;
+                            if let Ok(CompletionChunk::Usage(usage)) = chunk {
+                                ledger::record_completion(
+                                    purpose,
+                                    &ledger_provider,
+                                    &ledger_model,
+                                    custom_model.as_deref(),
+                                    *usage,
+                                );
+                            }
+                        })
+                        .boxed();
+                    return Ok(CompletionStreamResponse {
+                        stream,
+                        provider,
+                        model,
+                    });
+                }
+                Err(e) => {
+                    let can_fall_back = e.is_retryable() || e.is_provider_unavailable();
+                    errors.push(e);
+                    if !can_fall_back {
+                        break;
+                    }
+                }
+            }
+        }
+        Err(fallback_error(errors))
+    }
+
+    // asks for a response that follows the JSON schema of `T`, sending the parse error back to the
+    // model when it does not, up to `STRUCTURED_OUTPUT_MAX_ATTEMPTS` times
+    pub async fn complete_structured<T: DeserializeOwned + JsonSchema>(
+        mut self,
+    ) -> Result<T, LLMError> {
+        self.options.response_schema = Some(ResponseSchema::for_type::<T>());
+        let mut last_error = String::new();
+        for _ in 0..STRUCTURED_OUTPUT_MAX_ATTEMPTS {
+            let response = self.clone().do_request_full().await?;
+            let text = response.text();
+            match parse_structured_response::<T>(&text) {
+                Ok(value) => return Ok(value),
+                Err(e) => last_error = e.to_string(),
+            }
+            self.messages.push(Message {
+                role: Role::Assistant,
+                content: MessageContent::Text(text),
+            });
+            self.messages.push(Message {
+                role: Role::User,
+                content: MessageContent::Text(format!(
+                    "Your response is not valid JSON for the requested schema: {}. Respond again with only the corrected JSON object.",
+                    last_error
+                )),
+            });
+        }
+        Err(LLMError::StructuredOutputError {
+            attempts: STRUCTURED_OUTPUT_MAX_ATTEMPTS,
+            message: last_error,
+        })
+    }
+
+    // retries with a smaller context for as long as the provider says the request is too long
+    async fn complete_candidate(
+        &self,
+        provider: &Provider,
+        model: &Model,
+    ) -> Result<Completion, LLMError> {
+        let mut reductions = CONTEXT_REDUCTIONS.into_iter();
+        let mut reduced: Option<Vec<Message>> = None;
+        loop {
+            let messages = reduced.as_deref().unwrap_or(&self.messages);
+            let result = match resolve_provider(provider, model, messages, &self.options) {
+                Ok(resolved) => {
+                    resolved
+                        .llm_provider
+                        .complete(model, &resolved.messages, &resolved.options)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(LLMError::ContextLengthExceeded(error)) => {
+                    match reduce_context(model, messages, &mut reductions, &self.options) {
+                        Some(messages) => reduced = Some(messages),
+                        None => return Err(LLMError::ContextLengthExceeded(error)),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn stream_candidate(
+        &self,
+        provider: &Provider,
+        model: &Model,
+    ) -> Result<CompletionStream, LLMError> {
+        let mut reductions = CONTEXT_REDUCTIONS.into_iter();
+        let mut reduced: Option<Vec<Message>> = None;
+        loop {
+            let messages = reduced.as_deref().unwrap_or(&self.messages);
+            let result = match resolve_provider(provider, model, messages, &self.options) {
+                Ok(resolved) => {
+                    resolved
+                        .llm_provider
+                        .stream(model, &resolved.messages, &resolved.options)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(LLMError::ContextLengthExceeded(error)) => {
+                    match reduce_context(model, messages, &mut reductions, &self.options) {
+                        Some(messages) => reduced = Some(messages),
+                        None => return Err(LLMError::ContextLengthExceeded(error)),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn candidates(&self) -> Vec<(Provider, Model)> {
+        let mut candidates = vec![(self.provider.clone(), self.model.clone())];
+        candidates.extend(self.fallbacks.iter().cloned());
+        candidates
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Usage {
+    // for Anthropic, excludes the prompt tokens written to or read from the prompt cache
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    #[serde(default)]
+    pub cache_creation_input_tokens: u32,
+    #[serde(default)]
+    pub cache_read_input_tokens: u32,
+}
+
+#[derive(Debug, Clone)]
+pub enum CompletionChunk {
+    // a piece of the response text, in the order it was generated
+    Delta(String),
+    // a piece of the extended thinking, which comes before the response text
+    ThinkingDelta(String),
+    // sent once, after the last delta, if the provider reports token usage
+    Usage(Usage),
+}
+
+pub type CompletionStream = BoxStream<'static, Result<CompletionChunk, LLMError>>;
+
 #[derive(Debug, Clone)]
 pub struct CompletionOptions {
-    pub temperature: f64,
+    // `None` leaves the provider's default
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub stop: Vec<String>,
+    pub seed: Option<u64>,
+    pub candidate_count: Option<u32>,
     pub max_completion_tokens: i32,
     pub server_endpoint: Option<String>,
     pub custom_server_endpoint: Option<String>,
     pub custom_model: Option<String>,
+    pub custom_dialect: custom::CustomDialect,
+    pub tools: Vec<Tool>,
+    pub response_schema: Option<ResponseSchema>,
+    pub cache_breakpoints: Vec<usize>,
+    pub thinking_budget: Option<u32>,
+    pub image_descriptions: HashMap<usize, String>,
+    pub image_drop_order: Vec<usize>,
+    pub reasoning_effort: Option<ReasoningEffort>,
+    pub prefill: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
 }
 
 #[derive(Error, Debug)]
@@ -240,30 +925,282 @@ pub enum LLMError {
     RequestError(#[from] reqwest::Error),
     #[error("Failed to parse response: {0}")]
     ParseError(String),
+    #[error("Authentication failed: {0}")]
+    Authentication(ProviderError),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(ProviderError),
+    #[error("Rate limited: {0}")]
+    RateLimited(ProviderError),
+    #[error("Provider overloaded: {0}")]
+    Overloaded(ProviderError),
+    #[error("Provider server error: {0}")]
+    ServerError(ProviderError),
+    #[error("Context length exceeded: {0}")]
+    ContextLengthExceeded(ProviderError),
+    #[error("Refused by the content policy: {0}")]
+    ContentPolicy(ProviderError),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(ProviderError),
+    // non-2xx responses that fit none of the above
+    #[error("{0}")]
+    ApiError(ProviderError),
     #[error("LLM response is empty")]
     EmptyResponse,
     #[error("Images not supported by this provider")]
     ImagesNotSupported,
+    #[error("Provider not registered: {0}")]
+    ProviderNotRegistered(String),
+    #[error("Tools not supported by this provider")]
+    ToolsNotSupported,
+    #[error("Multiple candidates not supported by this provider")]
+    CandidatesNotSupported,
+    #[error("{model} does not support this request: {reason}")]
+    UnsupportedByModel { model: String, reason: String },
+    #[error("Response did not match the schema after {attempts} attempts: {message}")]
+    StructuredOutputError { attempts: usize, message: String },
+    #[error("Tool loop did not finish within {0} turns")]
+    ToolLoopLimitExceeded(usize),
+    #[error("LLM request cancelled")]
+    Cancelled,
+    // one error per provider tried, in the order of the fallback chain
+    #[error("Every provider failed: {}", join_errors(.0))]
+    AllCandidatesFailed(Vec<LLMError>),
     #[error("Other error: {0}")]
     Other(String),
 }
 
+// what a provider said about a failed request, parsed from its error JSON
+#[derive(Debug, Clone)]
+pub struct ProviderError {
+    pub provider: String,
+    pub status: u16,
+    // e.g. "rate_limit_error" (Anthropic), "context_length_exceeded" (OpenAI) or
+    // "RESOURCE_EXHAUSTED" (Gemini), `None` when the body has none
+    pub code: Option<String>,
+    pub message: String,
+    // from the `retry-after`, `retry-after-ms` or Anthropic rate limit reset headers
+    pub retry_after: Option<Duration>,
+}
+
+impl ProviderError {
+    // a failure of `provider` with only a status, for the tests
+    #[cfg(test)]
+    pub(crate) fn fixture(provider: &str, status: u16) -> Self {
+        Self {
+            provider: provider.to_string(),
+            status,
+            code: None,
+            message: "failed".to_string(),
+            retry_after: None,
+        }
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} API request failed with status {}",
+            self.provider, self.status
+        )?;
+        if let Some(code) = &self.code {
+            write!(f, " ({})", code)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl LLMError {
+    // transient failures that may succeed on another attempt or with another provider
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LLMError::RequestError(e) => e.is_connect() || e.is_timeout(),
+            LLMError::RateLimited(_) | LLMError::Overloaded(_) | LLMError::ServerError(_) => true,
+            _ => false,
+        }
+    }
+
+    // the provider's side of the failure, for errors returned by its API
+    pub fn provider_error(&self) -> Option<&ProviderError> {
+        match self {
+            LLMError::Authentication(error)
+            | LLMError::QuotaExceeded(error)
+            | LLMError::RateLimited(error)
+            | LLMError::Overloaded(error)
+            | LLMError::ServerError(error)
+            | LLMError::ContextLengthExceeded(error)
+            | LLMError::ContentPolicy(error)
+            | LLMError::InvalidRequest(error)
+            | LLMError::ApiError(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    // the errors of the providers that were tried, or this one alone
+    pub fn candidate_errors(&self) -> &[LLMError] {
+        match self {
+            LLMError::AllCandidatesFailed(errors) => errors,
+            e => std::slice::from_ref(e),
+        }
+    }
+
+    // errors that rule out one provider but not the next one in a fallback chain
+    fn is_provider_unavailable(&self) -> bool {
+        matches!(
+            self,
+            LLMError::Authentication(_)
+                | LLMError::QuotaExceeded(_)
+                | LLMError::ImagesNotSupported
+                | LLMError::ToolsNotSupported
+                | LLMError::CandidatesNotSupported
+                | LLMError::UnsupportedByModel { .. }
+                | LLMError::ProviderNotRegistered(_)
+                | LLMError::RequestBuildingError(_)
+        )
+    }
+}
+
+fn join_errors(errors: &[LLMError]) -> String {
+    errors
+        .iter()
+        .map(LLMError::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+// the error of a request whose candidates all failed, keeping each of their errors
+fn fallback_error(mut errors: Vec<LLMError>) -> LLMError {
+    match errors.len() {
+        0 => LLMError::EmptyResponse,
+        1 => errors.remove(0),
+        _ => LLMError::AllCandidatesFailed(errors),
+    }
+}
+
 pub async fn completion(
     model: Model,
     provider: Provider,
     messages: Vec<Message>,
     options: CompletionOptions,
 ) -> Result<String, LLMError> {
-    match provider {
-        Provider::OpenAI => openai::completion_openai(model, &messages, Some(&options)).await,
-        Provider::Anthropic => {
-            anthropic::completion_anthropic(model, &messages, Some(&options)).await
+    let resolved = resolve_provider(&provider, &model, &messages, &options)?;
+    resolved
+        .llm_provider
+        .complete(&model, &resolved.messages, &resolved.options)
+        .await
+        .map(|completion| completion.text())
+}
+
+pub async fn completion_stream(
+    model: Model,
+    provider: Provider,
+    messages: Vec<Message>,
+    options: CompletionOptions,
+) -> Result<CompletionStream, LLMError> {
+    let resolved = resolve_provider(&provider, &model, &messages, &options)?;
+    resolved
+        .llm_provider
+        .stream(&model, &resolved.messages, &resolved.options)
+        .await
+}
+
+// a provider with the messages and options rewritten for it and the model
+struct ResolvedProvider<'a> {
+    llm_provider: Arc<dyn LlmProvider>,
+    messages: Cow<'a, [Message]>,
+    options: Cow<'a, CompletionOptions>,
+}
+
+fn resolve_provider<'a>(
+    provider: &Provider,
+    model: &Model,
+    messages: &'a [Message],
+    options: &'a CompletionOptions,
+) -> Result<ResolvedProvider<'a>, LLMError> {
+    let llm_provider = match get_provider(provider.name()) {
+        Some(llm_provider) => llm_provider,
+        None => return Err(LLMError::ProviderNotRegistered(provider.to_string())),
+    };
+    if !llm_provider.capabilities().images && messages.iter().any(has_images) {
+        return Err(LLMError::ImagesNotSupported);
+    }
+    if !llm_provider.capabilities().tools && !options.tools.is_empty() {
+        return Err(LLMError::ToolsNotSupported);
+    }
+    if !llm_provider.capabilities().candidates && options.candidate_count.is_some_and(|n| n > 1) {
+        return Err(LLMError::CandidatesNotSupported);
+    }
+    let info = model_info(provider, model);
+    let system_prompt = info.as_ref().is_none_or(|info| info.system_prompt);
+    let (messages, options) = match normalize_messages(
+        messages,
+        &llm_provider.capabilities(),
+        system_prompt,
+        options.prefill,
+    ) {
+        Some((normalized, index_map)) => {
+            let mut options = options.clone();
+            let mut cache_breakpoints: Vec<usize> = options
+                .cache_breakpoints
+                .iter()
+                .filter_map(|&idx| index_map.get(idx).copied())
+                .collect();
+            cache_breakpoints.sort_unstable();
+            cache_breakpoints.dedup();
+            options.cache_breakpoints = cache_breakpoints;
+            (Cow::Owned(normalized), Cow::Owned(options))
         }
-        Provider::Google => gemini::completion_gemini(model, &messages, Some(&options)).await,
-        Provider::Fireworks => {
-            fireworks::completion_fireworks(model, &messages, Some(&options)).await
+        None => (Cow::Borrowed(messages), Cow::Borrowed(options)),
+    };
+    if let Some(info) = info {
+        validate_for_model(&info, &messages, &options)?;
+    }
+    Ok(ResolvedProvider {
+        llm_provider,
+        messages,
+        options,
+    })
+}
+
+// catches the requests the model is known to reject before they are sent
+fn validate_for_model(
+    info: &ModelInfo,
+    messages: &[Message],
+    options: &CompletionOptions,
+) -> Result<(), LLMError> {
+    let unsupported = |reason: String| LLMError::UnsupportedByModel {
+        model: info.id.clone(),
+        reason,
+    };
+    if !info.vision && messages.iter().any(has_images) {
+        return Err(unsupported("images are not supported".to_string()));
+    }
+    if options.max_completion_tokens > 0
+        && options.max_completion_tokens as u32 > info.max_output_tokens
+    {
+        return Err(unsupported(format!(
+            "max_completion_tokens {} is above the limit of {}",
+            options.max_completion_tokens, info.max_output_tokens
+        )));
+    }
+    // the thinking counts towards the output limit
+    if let Some(budget_tokens) = options.thinking_budget {
+        if budget_tokens >= info.max_output_tokens {
+            return Err(unsupported(format!(
+                "thinking budget {} leaves no room for the answer within the output limit of {}",
+                budget_tokens, info.max_output_tokens
+            )));
         }
-        Provider::Custom => custom::completion_custom(model, &messages, Some(&options)).await,
+    }
+    Ok(())
+}
+
+fn has_images(message: &Message) -> bool {
+    match &message.content {
+        MessageContent::Text(_) => false,
+        MessageContent::MultiContent(blocks) => blocks
+            .iter()
+            .any(|block| matches!(block, ContentBlock::Image { .. })),
     }
 }
 
@@ -274,13 +1211,7 @@ pub async fn default_completion(prompt: &Prompt) -> Result<String, LLMError> {
         .messages(prompt.clone().build_messages())
         .temperature(0.0)
         .build();
-    completion(
-        completion_request.model,
-        completion_request.provider,
-        completion_request.messages,
-        completion_request.options,
-    )
-    .await
+    completion_request.do_request().await
 }
 
 pub async fn default_cheap_completion(prompt: &Prompt) -> Result<String, LLMError> {
@@ -290,11 +1221,35 @@ pub async fn default_cheap_completion(prompt: &Prompt) -> Result<String, LLMErro
         .messages(prompt.clone().build_messages())
         .temperature(0.0)
         .build();
-    completion(
-        completion_request.model,
-        completion_request.provider,
-        completion_request.messages,
-        completion_request.options,
-    )
-    .await
+    completion_request.do_request().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback_error_keeps_every_candidate_error() {
+        let error = fallback_error(vec![
+            LLMError::Authentication(ProviderError::fixture("anthropic", 401)),
+            LLMError::RateLimited(ProviderError::fixture("openai", 429)),
+        ]);
+        let errors = error.candidate_errors();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], LLMError::Authentication(_)));
+        assert!(matches!(errors[1], LLMError::RateLimited(_)));
+    }
+
+    #[test]
+    fn fallback_error_of_one_candidate_is_its_error() {
+        let error = fallback_error(vec![LLMError::Overloaded(ProviderError::fixture(
+            "anthropic",
+            529,
+        ))]);
+        assert!(matches!(error, LLMError::Overloaded(_)));
+        assert!(matches!(
+            fallback_error(Vec::new()),
+            LLMError::EmptyResponse
+        ));
+    }
 }
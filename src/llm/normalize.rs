@@ -0,0 +1,269 @@
+use crate::llm::provider::{ProviderCapabilities, SystemMessages};
+use crate::llm::{ContentBlock, Message, MessageContent, Role};
+
+// stands in for a missing first or last user message where the provider requires one
+const LEADING_USER_MESSAGE: &str = "(The conversation starts here.)";
+const TRAILING_USER_MESSAGE: &str = "Continue.";
+
+// a message of the normalized conversation and the indexes of the messages it was built from
+struct Entry {
+    message: Message,
+    sources: Vec<usize>,
+}
+
+// rewrites the messages into a conversation the provider and model accept, or `None` if they
+// already do; also returns the index each message ended up at, for the options that index the
+// messages. Without `system_prompt`, the system messages go at the start of the first user message.
+// A trailing assistant message is left last when the request is a `prefill`
+pub(crate) fn normalize_messages(
+    messages: &[Message],
+    capabilities: &ProviderCapabilities,
+    system_prompt: bool,
+    prefill: bool,
+) -> Option<(Vec<Message>, Vec<usize>)> {
+    let system_idxs: Vec<usize> = (0..messages.len())
+        .filter(|&idx| messages[idx].role == Role::System)
+        .collect();
+    let hoist = !system_idxs.is_empty()
+        && (!system_prompt
+            || (capabilities.system_messages == SystemMessages::Leading && system_idxs != [0]));
+    let mut changed = hoist;
+    let mut entries: Vec<Entry> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| !hoist || message.role != Role::System)
+        .map(|(idx, message)| Entry {
+            message: message.clone(),
+            sources: vec![idx],
+        })
+        .collect();
+    if hoist {
+        let system_text = system_idxs
+            .iter()
+            .map(|&idx| text_of(&messages[idx].content))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        if system_prompt {
+            entries.insert(
+                0,
+                Entry {
+                    message: Message {
+                        role: Role::System,
+                        content: MessageContent::Text(system_text),
+                    },
+                    sources: system_idxs,
+                },
+            );
+        } else {
+            match entries
+                .iter_mut()
+                .find(|entry| entry.message.role == Role::User)
+            {
+                Some(entry) => {
+                    let mut blocks = vec![ContentBlock::Text { text: system_text }];
+                    blocks.extend(into_blocks(entry.message.content.clone()));
+                    entry.message.content = MessageContent::MultiContent(blocks);
+                    entry.sources.extend(system_idxs);
+                }
+                None => entries.insert(
+                    0,
+                    Entry {
+                        message: Message {
+                            role: Role::User,
+                            content: MessageContent::Text(system_text),
+                        },
+                        sources: system_idxs,
+                    },
+                ),
+            }
+        }
+    }
+
+    if capabilities.alternating_roles {
+        let mut merged: Vec<Entry> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match merged.last_mut() {
+                Some(last)
+                    if last.message.role == entry.message.role
+                        && entry.message.role != Role::System =>
+                {
+                    let mut blocks = into_blocks(last.message.content.clone());
+                    blocks.extend(into_blocks(entry.message.content));
+                    last.message.content = MessageContent::MultiContent(blocks);
+                    last.sources.extend(entry.sources);
+                    changed = true;
+                }
+                _ => merged.push(entry),
+            }
+        }
+        entries = merged;
+    }
+
+    if capabilities.user_first {
+        let first = entries
+            .iter()
+            .position(|entry| entry.message.role != Role::System);
+        if let Some(first) = first.filter(|&first| entries[first].message.role != Role::User) {
+            entries.insert(first, user_entry(LEADING_USER_MESSAGE));
+            changed = true;
+        }
+    }
+
+    let trailing_user_message = match entries.last().map(|entry| &entry.message.role) {
+        Some(Role::User) => false,
+        Some(Role::Assistant) => !capabilities.trailing_assistant && !prefill,
+        _ => capabilities.alternating_roles,
+    };
+    if trailing_user_message {
+        entries.push(user_entry(TRAILING_USER_MESSAGE));
+        changed = true;
+    }
+
+    if !changed {
+        return None;
+    }
+    let mut index_map = vec![0; messages.len()];
+    for (new_idx, entry) in entries.iter().enumerate() {
+        for &idx in &entry.sources {
+            index_map[idx] = new_idx;
+        }
+    }
+    Some((
+        entries.into_iter().map(|entry| entry.message).collect(),
+        index_map,
+    ))
+}
+
+fn user_entry(text: &str) -> Entry {
+    Entry {
+        message: Message {
+            role: Role::User,
+            content: MessageContent::Text(text.to_string()),
+        },
+        sources: Vec::new(),
+    }
+}
+
+fn into_blocks(content: MessageContent) -> Vec<ContentBlock> {
+    match content {
+        MessageContent::Text(text) => vec![ContentBlock::Text { text }],
+        MessageContent::MultiContent(blocks) => blocks,
+    }
+}
+
+// system messages are text only
+fn text_of(content: &MessageContent) -> String {
+    match content {
+        MessageContent::Text(text) => text.clone(),
+        MessageContent::MultiContent(blocks) => blocks
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(alternating_roles: bool, user_first: bool) -> ProviderCapabilities {
+        ProviderCapabilities {
+            images: true,
+            streaming: true,
+            tools: false,
+            candidates: false,
+            system_messages: SystemMessages::Leading,
+            alternating_roles,
+            user_first,
+            trailing_assistant: !alternating_roles,
+        }
+    }
+
+    fn message(role: Role, text: &str) -> Message {
+        Message {
+            role,
+            content: MessageContent::Text(text.to_string()),
+        }
+    }
+
+    fn texts(message: &Message) -> Vec<String> {
+        match &message.content {
+            MessageContent::Text(text) => vec![text.clone()],
+            MessageContent::MultiContent(blocks) => blocks
+                .iter()
+                .filter_map(|block| match block {
+                    ContentBlock::Text { text } => Some(text.clone()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn merges_consecutive_messages_and_remaps_their_indexes() {
+        let messages = vec![
+            message(Role::System, "be brief"),
+            message(Role::User, "screenshot"),
+            message(Role::User, "question"),
+            message(Role::Assistant, "answer"),
+            message(Role::User, "follow-up"),
+        ];
+        let (normalized, index_map) =
+            normalize_messages(&messages, &capabilities(true, true), true, false).unwrap();
+        let roles: Vec<Role> = normalized.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(
+            roles,
+            [Role::System, Role::User, Role::Assistant, Role::User]
+        );
+        assert_eq!(texts(&normalized[1]), ["screenshot", "question"]);
+        assert_eq!(index_map, [0, 1, 1, 2, 3]);
+    }
+
+    #[test]
+    fn hoists_system_messages_and_remaps_the_others() {
+        let messages = vec![
+            message(Role::User, "question"),
+            message(Role::System, "late instructions"),
+            message(Role::Assistant, "answer"),
+            message(Role::User, "follow-up"),
+        ];
+        let (normalized, index_map) =
+            normalize_messages(&messages, &capabilities(false, true), true, false).unwrap();
+        assert_eq!(normalized[0].role, Role::System);
+        assert_eq!(texts(&normalized[0]), ["late instructions"]);
+        assert_eq!(index_map, [1, 0, 2, 3]);
+    }
+
+    #[test]
+    fn starts_with_a_user_message_without_alternating_roles() {
+        let messages = vec![
+            message(Role::System, "be brief"),
+            message(Role::Assistant, "How can I help?"),
+            message(Role::User, "question"),
+        ];
+        let (normalized, index_map) =
+            normalize_messages(&messages, &capabilities(false, true), true, false).unwrap();
+        assert_eq!(normalized[1].role, Role::User);
+        assert_eq!(texts(&normalized[1]), [LEADING_USER_MESSAGE]);
+        assert_eq!(index_map, [0, 2, 3]);
+        assert!(normalize_messages(&messages, &capabilities(false, false), true, false).is_none());
+    }
+
+    #[test]
+    fn continues_a_trailing_assistant_message_only_where_rejected() {
+        let messages = vec![
+            message(Role::User, "question"),
+            message(Role::Assistant, "The answer is"),
+        ];
+        assert!(normalize_messages(&messages, &capabilities(false, true), true, false).is_none());
+        let (normalized, _) =
+            normalize_messages(&messages, &capabilities(true, true), true, false).unwrap();
+        assert_eq!(texts(normalized.last().unwrap()), [TRAILING_USER_MESSAGE]);
+        // a prefill is never followed by another message
+        assert!(normalize_messages(&messages, &capabilities(true, true), true, true).is_none());
+    }
+}
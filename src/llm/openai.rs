@@ -1,24 +1,109 @@
-use crate::llm::{CompletionOptions, ContentBlock, LLMError, Message, MessageContent, Model};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use crate::http_client::send_with_retries;
+use crate::llm::catalog::model_info;
+use crate::llm::endpoint::openai_target;
+use crate::llm::errors;
+use crate::llm::provider::{LlmProvider, ProviderCapabilities, SystemMessages};
+use crate::llm::streaming::{completion_stream, parse_json_event, sse_events};
+use crate::llm::{
+    Completion, CompletionChunk, CompletionOptions, CompletionStream, ContentBlock, LLMError,
+    Message, MessageContent, Model, Provider, ReasoningEffort, ResponseSchema, Tool, Usage,
+};
+use async_trait::async_trait;
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
-use std::env;
-
-const OPENAI_API_URL: &str = "https://api.openai.com/v1/chat/completions";
 
 #[derive(Serialize)]
-struct RequestBody<'a> {
+struct RequestBody {
     model: String,
-    messages: Vec<OpenAIMessage<'a>>,
+    messages: Vec<OpenAIMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<i32>,
+    // reasoning models count their hidden reasoning in it too
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<ReasoningEffort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<OpenAITool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<OpenAIResponseFormat>,
 }
 
+// also used by the OpenAI-compatible providers
 #[derive(Serialize)]
-struct OpenAIMessage<'a> {
-    role: &'a str,
-    content: &'a [OpenAIContentBlock],
+pub(crate) struct StreamOptions {
+    pub include_usage: bool,
+}
+
+#[derive(Serialize)]
+struct OpenAITool {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    function: OpenAIFunction,
+}
+
+#[derive(Serialize)]
+struct OpenAIFunction {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+// also used by the OpenAI-compatible providers
+#[derive(Serialize)]
+pub(crate) struct OpenAIResponseFormat {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    json_schema: OpenAIJsonSchema,
+}
+
+#[derive(Serialize)]
+struct OpenAIJsonSchema {
+    name: String,
+    schema: serde_json::Value,
+    // strict mode rejects schemas with optional fields, which derived schemas often have
+    strict: bool,
+}
+
+// also used by the OpenAI-compatible providers
+#[derive(Serialize)]
+pub(crate) struct OpenAIMessage {
+    role: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    content: Vec<OpenAIContentBlock>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OpenAIToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenAIToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    type_: String,
+    function: OpenAIFunctionCall,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenAIFunctionCall {
+    name: String,
+    // JSON encoded
+    arguments: String,
 }
 
 #[derive(Serialize)]
@@ -44,6 +129,7 @@ enum OpenAIContentBlock {
 #[derive(Deserialize)]
 struct Response {
     choices: Vec<Choice>,
+    usage: Option<ResponseUsage>,
 }
 
 #[derive(Deserialize)]
@@ -53,116 +139,336 @@ struct Choice {
 
 #[derive(Deserialize)]
 struct ResponseMessage {
-    content: String,
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAIToolCall>,
+}
+
+#[derive(Deserialize)]
+struct StreamResponse {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    usage: Option<ResponseUsage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    index: usize,
+    delta: StreamDelta,
+}
+
+#[derive(Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponseUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+pub struct OpenAIProvider;
+
+#[async_trait]
+impl LlmProvider for OpenAIProvider {
+    async fn complete(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, LLMError> {
+        completion_openai(model.clone(), messages, Some(options)).await
+    }
+
+    async fn stream(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<CompletionStream, LLMError> {
+        stream_openai(model.clone(), messages, Some(options)).await
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities {
+            images: true,
+            streaming: true,
+            tools: true,
+            candidates: true,
+            system_messages: SystemMessages::Anywhere,
+            alternating_roles: false,
+            user_first: false,
+            trailing_assistant: true,
+        }
+    }
 }
 
 pub(crate) async fn completion_openai(
     model: Model,
     messages: &[Message],
     options: Option<&CompletionOptions>,
-) -> Result<String, LLMError> {
-    let headers = match build_openai_request_headers() {
-        Ok(headers) => headers,
-        Err(e) => return Err(e),
+) -> Result<Completion, LLMError> {
+    let response = send_openai_request(model, messages, options, false).await?;
+    read_openai_response(response).await
+}
+
+pub(crate) async fn stream_openai(
+    model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+) -> Result<CompletionStream, LLMError> {
+    let response = send_openai_request(model, messages, options, true).await?;
+    Ok(openai_completion_stream(response))
+}
+
+// also used by the OpenAI-compatible providers
+pub(crate) async fn read_openai_response(
+    response: reqwest::Response,
+) -> Result<Completion, LLMError> {
+    let response_body: Response = match response.json().await {
+        Ok(body) => body,
+        Err(e) => return Err(LLMError::RequestError(e)),
     };
-    let openai_messages: Vec<_> = messages
-        .iter()
-        .map(|msg| {
-            let role = match msg.role {
-                crate::llm::Role::User => "user",
-                crate::llm::Role::Assistant => "assistant",
-                crate::llm::Role::System => "system",
-            };
-
-            let content = match &msg.content {
-                MessageContent::Text(text) => vec![OpenAIContentBlock::Text {
-                    type_: "text",
-                    text: text.clone(),
-                }],
-                MessageContent::MultiContent(blocks) => blocks
-                    .iter()
-                    .map(|block| match block {
-                        ContentBlock::Text { text } => OpenAIContentBlock::Text {
-                            type_: "text",
-                            text: text.clone(),
-                        },
-                        ContentBlock::Image { source } => OpenAIContentBlock::Image {
-                            type_: "image_url",
-                            image_url: ImageURL {
-                                url: format!("data:image/jpeg;base64,{}", source.data),
-                            },
-                        },
-                    })
-                    .collect(),
-            };
-            (role, content)
+
+    let mut candidates = response_body
+        .choices
+        .into_iter()
+        .map(|choice| build_content(choice.message));
+    let content = match candidates.next() {
+        Some(content) => content,
+        None => return Err(LLMError::EmptyResponse),
+    };
+    let alternatives = candidates.collect();
+    let usage = response_body
+        .usage
+        .map(|usage| Usage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+            ..Default::default()
         })
-        .collect();
+        .unwrap_or_default();
+    Ok(Completion {
+        content,
+        alternatives,
+        usage,
+    })
+}
 
-    let openai_messages: Vec<OpenAIMessage> = openai_messages
-        .iter()
-        .map(|(role, content)| OpenAIMessage { role, content })
+fn build_content(message: ResponseMessage) -> Vec<ContentBlock> {
+    let mut content = Vec::new();
+    if let Some(text) = message.content {
+        content.push(ContentBlock::Text { text });
+    }
+    for tool_call in message.tool_calls {
+        // keep malformed arguments as a string so the tool handler can report them
+        let input = serde_json::from_str(&tool_call.function.arguments)
+            .unwrap_or(serde_json::Value::String(tool_call.function.arguments));
+        content.push(ContentBlock::ToolUse {
+            id: tool_call.id,
+            name: tool_call.function.name,
+            input,
+        });
+    }
+    content
+}
+
+pub(crate) fn openai_completion_stream(response: reqwest::Response) -> CompletionStream {
+    completion_stream(sse_events(response), (), |_, event| {
+        if event.data == "[DONE]" {
+            return Ok(Vec::new());
+        }
+        let chunk: StreamResponse = parse_json_event(&event.data)?;
+        Ok(parse_openai_stream_chunk(chunk))
+    })
+}
+
+fn parse_openai_stream_chunk(chunk: StreamResponse) -> Vec<CompletionChunk> {
+    let mut chunks: Vec<CompletionChunk> = chunk
+        .choices
+        .into_iter()
+        // only the first candidate is streamed
+        .filter(|choice| choice.index == 0)
+        .filter_map(|choice| choice.delta.content)
+        .filter(|content| !content.is_empty())
+        .map(CompletionChunk::Delta)
         .collect();
+    if let Some(usage) = chunk.usage {
+        chunks.push(CompletionChunk::Usage(Usage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+            ..Default::default()
+        }));
+    }
+    chunks
+}
+
+async fn send_openai_request(
+    model: Model,
+    messages: &[Message],
+    options: Option<&CompletionOptions>,
+    stream: bool,
+) -> Result<reqwest::Response, LLMError> {
+    let (url, mut headers) = openai_target(
+        &model.to_string(),
+        "chat/completions",
+        options.and_then(|opt| opt.server_endpoint.as_deref()),
+    )?;
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let reasoning = model_info(&Provider::OpenAI, &model).is_some_and(|info| info.reasoning);
+    let mut openai_messages = build_openai_messages(messages);
+    if reasoning {
+        for message in &mut openai_messages {
+            if message.role == "system" {
+                message.role = "developer";
+            }
+        }
+    }
+    let max_tokens = options
+        .and_then(|opt| (opt.max_completion_tokens != 0).then_some(opt.max_completion_tokens));
+    // reasoning models reject any sampling parameter other than the default, and stop sequences
     let req_body = RequestBody {
         model: model.to_string(),
         messages: openai_messages,
-        temperature: options.and_then(|opt| (opt.temperature != 0.0).then_some(opt.temperature)),
-        max_tokens: options
-            .and_then(|opt| (opt.max_completion_tokens != 0).then_some(opt.max_completion_tokens)),
+        temperature: options
+            .and_then(|opt| opt.temperature)
+            .filter(|_| !reasoning),
+        top_p: options.and_then(|opt| opt.top_p).filter(|_| !reasoning),
+        stop: options
+            .map(|opt| opt.stop.clone())
+            .filter(|_| !reasoning)
+            .unwrap_or_default(),
+        seed: options.and_then(|opt| opt.seed),
+        n: options.and_then(|opt| opt.candidate_count),
+        max_tokens: max_tokens.filter(|_| !reasoning),
+        max_completion_tokens: max_tokens.filter(|_| reasoning),
+        reasoning_effort: options
+            .and_then(|opt| opt.reasoning_effort)
+            .filter(|_| reasoning),
+        stream: stream.then_some(true),
+        stream_options: stream.then_some(StreamOptions {
+            include_usage: true,
+        }),
+        tools: options
+            .map(|opt| build_openai_tools(&opt.tools))
+            .unwrap_or_default(),
+        response_format: options
+            .and_then(|opt| opt.response_schema.as_ref())
+            .map(build_openai_response_format),
     };
-    let client = reqwest::Client::new();
-    let response = match client
-        .post(OPENAI_API_URL)
-        .headers(headers)
-        .json(&req_body)
-        .send()
-        .await
+    let response = match send_with_retries(|client| {
+        client.post(&url).headers(headers.clone()).json(&req_body)
+    })
+    .await
     {
         Ok(resp) => resp,
         Err(e) => return Err(LLMError::RequestError(e)),
     };
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unable to read error response".to_string());
-        return Err(LLMError::Other(
-            format!(
-                "OpenAI API request failed with status {}: {}",
-                status, error_text
-            )
-            .into(),
-        ));
+    if !response.status().is_success() {
+        return Err(errors::from_response("OpenAI", response).await);
     }
+    Ok(response)
+}
 
-    let response_body: Response = match response.json().await {
-        Ok(body) => body,
-        Err(e) => return Err(LLMError::RequestError(e)),
-    };
+// tool results become separate `tool` messages ahead of the rest of the user message
+pub(crate) fn build_openai_messages(messages: &[Message]) -> Vec<OpenAIMessage> {
+    let mut openai_messages = Vec::new();
+    for msg in messages {
+        let role = match msg.role {
+            crate::llm::Role::User => "user",
+            crate::llm::Role::Assistant => "assistant",
+            crate::llm::Role::System => "system",
+        };
 
-    response_body
-        .choices
-        .first()
-        .map(|choice| choice.message.content.clone())
-        .ok_or(LLMError::EmptyResponse)
-}
-
-fn build_openai_request_headers() -> Result<HeaderMap, LLMError> {
-    let api_key = match env::var("OPENAI_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            return Err(LLMError::RequestBuildingError(
-                "OPENAI_API_KEY environment variable not set".to_string(),
-            ))
+        let blocks = match &msg.content {
+            MessageContent::Text(text) => {
+                openai_messages.push(OpenAIMessage {
+                    role,
+                    content: vec![OpenAIContentBlock::Text {
+                        type_: "text",
+                        text: text.clone(),
+                    }],
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                });
+                continue;
+            }
+            MessageContent::MultiContent(blocks) => blocks,
+        };
+        let mut content = Vec::new();
+        let mut tool_calls = Vec::new();
+        for block in blocks {
+            match block {
+                ContentBlock::Text { text } => content.push(OpenAIContentBlock::Text {
+                    type_: "text",
+                    text: text.clone(),
+                }),
+                ContentBlock::Image { source } => content.push(OpenAIContentBlock::Image {
+                    type_: "image_url",
+                    image_url: ImageURL {
+                        url: format!("data:{};base64,{}", source.media_type, source.data),
+                    },
+                }),
+                ContentBlock::ToolUse { id, name, input } => tool_calls.push(OpenAIToolCall {
+                    id: id.clone(),
+                    type_: "function".to_string(),
+                    function: OpenAIFunctionCall {
+                        name: name.clone(),
+                        arguments: input.to_string(),
+                    },
+                }),
+                ContentBlock::ToolResult {
+                    tool_use_id,
+                    content: result,
+                    ..
+                } => openai_messages.push(OpenAIMessage {
+                    role: "tool",
+                    content: vec![OpenAIContentBlock::Text {
+                        type_: "text",
+                        text: result.clone(),
+                    }],
+                    tool_calls: Vec::new(),
+                    tool_call_id: Some(tool_use_id.clone()),
+                }),
+                ContentBlock::Thinking { .. } | ContentBlock::RedactedThinking { .. } => (),
+            }
         }
-    };
-    let mut headers = HeaderMap::new();
-    let auth_header = match HeaderValue::from_str(&format!("Bearer {api_key}")) {
-        Ok(header) => header,
-        Err(e) => return Err(LLMError::RequestBuildingError(e.to_string())),
-    };
-    headers.insert(AUTHORIZATION, auth_header);
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    Ok(headers)
+        if !content.is_empty() || !tool_calls.is_empty() {
+            openai_messages.push(OpenAIMessage {
+                role,
+                content,
+                tool_calls,
+                tool_call_id: None,
+            });
+        }
+    }
+    openai_messages
+}
+
+fn build_openai_tools(tools: &[Tool]) -> Vec<OpenAITool> {
+    tools
+        .iter()
+        .map(|tool| OpenAITool {
+            type_: "function",
+            function: OpenAIFunction {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                parameters: tool.input_schema.clone(),
+            },
+        })
+        .collect()
+}
+
+pub(crate) fn build_openai_response_format(
+    response_schema: &ResponseSchema,
+) -> OpenAIResponseFormat {
+    OpenAIResponseFormat {
+        type_: "json_schema",
+        json_schema: OpenAIJsonSchema {
+            name: response_schema.name.clone(),
+            schema: response_schema.schema.clone(),
+            strict: false,
+        },
+    }
 }
@@ -0,0 +1,53 @@
+use crate::llm::catalog::model_info;
+use crate::llm::{Model, Provider, Usage};
+
+// USD per million tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input_per_million_tokens: f64,
+    pub output_per_million_tokens: f64,
+}
+
+impl ModelPrice {
+    pub(crate) const fn new(input_per_million_tokens: f64, output_per_million_tokens: f64) -> Self {
+        Self {
+            input_per_million_tokens,
+            output_per_million_tokens,
+        }
+    }
+}
+
+// text-embedding-3-small
+pub const EMBEDDING_PRICE_PER_MILLION_TOKENS: f64 = 0.02;
+// whisper-1
+pub const TRANSCRIPTION_PRICE_PER_MINUTE: f64 = 0.006;
+// prompt cache writes and reads, relative to the input price
+pub const CACHE_WRITE_PRICE_MULTIPLIER: f64 = 1.25;
+pub const CACHE_READ_PRICE_MULTIPLIER: f64 = 0.1;
+
+// `None` when the price is unknown, e.g. for a custom server
+pub fn model_price(provider: &Provider, model: &Model) -> Option<ModelPrice> {
+    model_info(provider, model).and_then(|info| info.price)
+}
+
+pub fn completion_cost(provider: &Provider, model: &Model, usage: Usage) -> Option<f64> {
+    model_price(provider, model).map(|price| {
+        (usage.input_tokens as f64 * price.input_per_million_tokens
+            + usage.cache_creation_input_tokens as f64
+                * price.input_per_million_tokens
+                * CACHE_WRITE_PRICE_MULTIPLIER
+            + usage.cache_read_input_tokens as f64
+                * price.input_per_million_tokens
+                * CACHE_READ_PRICE_MULTIPLIER
+            + usage.output_tokens as f64 * price.output_per_million_tokens)
+            / 1_000_000.0
+    })
+}
+
+pub fn embedding_cost(input_tokens: u32) -> f64 {
+    input_tokens as f64 * EMBEDDING_PRICE_PER_MILLION_TOKENS / 1_000_000.0
+}
+
+pub fn transcription_cost(duration_seconds: f64) -> f64 {
+    duration_seconds / 60.0 * TRANSCRIPTION_PRICE_PER_MINUTE
+}
@@ -0,0 +1,94 @@
+use crate::llm::{
+    anthropic, custom, fireworks, gemini, openai, Completion, CompletionChunk, CompletionOptions,
+    CompletionStream, LLMError, Message, Model,
+};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProviderCapabilities {
+    pub images: bool,
+    pub streaming: bool,
+    pub tools: bool,
+    // more than one candidate per request
+    pub candidates: bool,
+    pub system_messages: SystemMessages,
+    // consecutive messages of the same role are merged
+    pub alternating_roles: bool,
+    // the first message after the system ones is a user message
+    pub user_first: bool,
+    // a conversation may end with an assistant message, which the response continues
+    pub trailing_assistant: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemMessages {
+    Anywhere,
+    // the system messages are concatenated into a single one, before the others
+    Leading,
+}
+
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    async fn complete(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, LLMError>;
+
+    // providers without native streaming send the whole completion text as a single delta
+    async fn stream(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<CompletionStream, LLMError> {
+        let completion = self.complete(model, messages, options).await?;
+        Ok(stream::iter(vec![
+            Ok(CompletionChunk::Delta(completion.text())),
+            Ok(CompletionChunk::Usage(completion.usage)),
+        ])
+        .boxed())
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities;
+}
+
+static REGISTRY: OnceLock<RwLock<HashMap<String, Arc<dyn LlmProvider>>>> = OnceLock::new();
+
+fn registry() -> &'static RwLock<HashMap<String, Arc<dyn LlmProvider>>> {
+    REGISTRY.get_or_init(|| {
+        let mut providers: HashMap<String, Arc<dyn LlmProvider>> = HashMap::new();
+        providers.insert("openai".to_string(), Arc::new(openai::OpenAIProvider));
+        providers.insert(
+            "anthropic".to_string(),
+            Arc::new(anthropic::AnthropicProvider),
+        );
+        providers.insert("google".to_string(), Arc::new(gemini::GeminiProvider));
+        providers.insert(
+            "fireworks".to_string(),
+            Arc::new(fireworks::FireworksProvider),
+        );
+        providers.insert("custom".to_string(), Arc::new(custom::CustomProvider));
+        RwLock::new(providers)
+    })
+}
+
+// registering under the name of a built-in provider replaces it
+pub fn register_provider(name: impl Into<String>, provider: Arc<dyn LlmProvider>) {
+    registry()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name.into(), provider);
+}
+
+pub fn get_provider(name: &str) -> Option<Arc<dyn LlmProvider>> {
+    registry()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+        .cloned()
+}
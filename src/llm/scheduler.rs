@@ -0,0 +1,390 @@
+use crate::ledger::Purpose;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    // the user is waiting on the answer
+    Interactive,
+    // the screenshot descriptions, embeddings and redundancy checks
+    Background,
+}
+
+impl Priority {
+    pub fn for_purpose(purpose: Purpose) -> Self {
+        match purpose {
+            Purpose::Description | Purpose::RedundancyCheck => Priority::Background,
+            Purpose::Chat | Purpose::Autocomplete | Purpose::Other => Priority::Interactive,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    // requests in flight at once per provider name, e.g. "anthropic"
+    pub max_concurrent: HashMap<String, usize>,
+    pub default_max_concurrent: usize,
+    // slots of each provider that background requests never take, so interactive requests do not
+    // queue behind them
+    pub interactive_reserve: usize,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: HashMap::new(),
+            default_max_concurrent: 4,
+            interactive_reserve: 1,
+        }
+    }
+}
+
+// shared by the handles of a job, which stops as soon as any of them cancels it
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    state: Arc<CancelState>,
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        self.state.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.state.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProviderSlots {
+    in_flight: usize,
+    waiting_interactive: usize,
+}
+
+static CONFIG: OnceLock<SchedulerConfig> = OnceLock::new();
+static SLOTS: OnceLock<Mutex<HashMap<String, ProviderSlots>>> = OnceLock::new();
+static RELEASED: Notify = Notify::const_new();
+
+// must be called before the first request, returns the config back if it is too late
+pub fn configure(config: SchedulerConfig) -> Result<(), SchedulerConfig> {
+    CONFIG.set(config)
+}
+
+fn config() -> &'static SchedulerConfig {
+    CONFIG.get_or_init(SchedulerConfig::default)
+}
+
+fn slots() -> &'static Mutex<HashMap<String, ProviderSlots>> {
+    SLOTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// a request slot of one provider, given back when dropped
+#[derive(Debug)]
+pub(crate) struct Permit {
+    provider: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(slots) = slots()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(&self.provider)
+        {
+            slots.in_flight -= 1;
+        }
+        RELEASED.notify_waiters();
+    }
+}
+
+// counts an interactive request as waiting until it is admitted or given up on
+struct Waiting<'a> {
+    provider: &'a str,
+    registered: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if !self.registered {
+            return;
+        }
+        if let Some(slots) = slots()
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(self.provider)
+        {
+            slots.waiting_interactive -= 1;
+        }
+        // background requests held back by this one may go now
+        RELEASED.notify_waiters();
+    }
+}
+
+fn try_admit(provider: &str, priority: Priority, waiting: &mut Waiting) -> Option<Permit> {
+    let config = config();
+    let limit = config
+        .max_concurrent
+        .get(provider)
+        .copied()
+        .unwrap_or(config.default_max_concurrent)
+        .max(1);
+    let mut all_slots = slots().lock().unwrap_or_else(|e| e.into_inner());
+    let slots = all_slots.entry(provider.to_string()).or_default();
+    let admitted = match priority {
+        Priority::Interactive => slots.in_flight < limit,
+        // background requests also wait for every interactive one that is waiting
+        Priority::Background => {
+            slots.waiting_interactive == 0
+                && slots.in_flight < limit.saturating_sub(config.interactive_reserve).max(1)
+        }
+    };
+    if admitted {
+        slots.in_flight += 1;
+        if waiting.registered {
+            slots.waiting_interactive -= 1;
+            waiting.registered = false;
+        }
+        return Some(Permit {
+            provider: provider.to_string(),
+        });
+    }
+    if priority == Priority::Interactive && !waiting.registered {
+        slots.waiting_interactive += 1;
+        waiting.registered = true;
+    }
+    None
+}
+
+// waits for a slot of `provider`, or returns `None` once `cancel` is cancelled
+pub(crate) async fn acquire(
+    provider: &str,
+    priority: Priority,
+    cancel: Option<&CancelToken>,
+) -> Option<Permit> {
+    let mut waiting = Waiting {
+        provider,
+        registered: false,
+    };
+    loop {
+        let released = RELEASED.notified();
+        tokio::pin!(released);
+        released.as_mut().enable();
+        if cancel.is_some_and(CancelToken::is_cancelled) {
+            return None;
+        }
+        if let Some(permit) = try_admit(provider, priority, &mut waiting) {
+            return Some(permit);
+        }
+        match cancel {
+            Some(cancel) => {
+                tokio::select! {
+                    _ = released => (),
+                    _ = cancel.cancelled() => return None,
+                }
+            }
+            None => released.await,
+        }
+    }
+}
+
+// the slot of the request running in a task, given back while it waits to retry
+struct HeldSlot {
+    provider: String,
+    priority: Priority,
+    cancel: Option<CancelToken>,
+    permit: Option<Permit>,
+}
+
+tokio::task_local! {
+    static HELD_SLOT: RefCell<Option<HeldSlot>>;
+}
+
+// runs `future` in a slot of `provider`, and returns its output with the slot once it is done, or
+// `None` once `cancel` is cancelled while waiting for the slot
+pub(crate) async fn with_slot<F: Future>(
+    provider: &str,
+    priority: Priority,
+    cancel: Option<&CancelToken>,
+    future: F,
+) -> Option<(F::Output, Option<Permit>)> {
+    let permit = acquire(provider, priority, cancel).await?;
+    let held = HeldSlot {
+        provider: provider.to_string(),
+        priority,
+        cancel: cancel.cloned(),
+        permit: Some(permit),
+    };
+    HELD_SLOT
+        .scope(RefCell::new(Some(held)), async move {
+            let output = future.await;
+            let permit =
+                HELD_SLOT.with(|slot| slot.borrow_mut().take().and_then(|held| held.permit));
+            Some((output, permit))
+        })
+        .await
+}
+
+// gives the slot held by the request running in this task back to the others for the duration of
+// `wait`, e.g. a retry backoff, and then waits for a slot again
+pub(crate) async fn release_while<F: Future>(wait: F) -> F::Output {
+    let held = HELD_SLOT
+        .try_with(|slot| {
+            slot.borrow_mut().as_mut().map(|held| {
+                held.permit = None;
+                (held.provider.clone(), held.priority, held.cancel.clone())
+            })
+        })
+        .ok()
+        .flatten();
+    let output = wait.await;
+    if let Some((provider, priority, cancel)) = held {
+        let permit = acquire(&provider, priority, cancel.as_ref()).await;
+        let _ = HELD_SLOT.try_with(|slot| {
+            if let Some(held) = slot.borrow_mut().as_mut() {
+                held.permit = permit;
+            }
+        });
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // each test uses its own provider name, since the slots are shared by the whole process;
+    // the default config allows 4 requests per provider and reserves 1 of them
+    async fn admitted(
+        provider: &'static str,
+        priority: Priority,
+        cancel: Option<&CancelToken>,
+    ) -> Option<Permit> {
+        tokio::time::timeout(
+            Duration::from_millis(50),
+            acquire(provider, priority, cancel),
+        )
+        .await
+        .ok()
+        .flatten()
+    }
+
+    #[tokio::test]
+    async fn background_requests_leave_the_reserve_to_interactive_ones() {
+        let mut permits = Vec::new();
+        for _ in 0..3 {
+            permits.push(
+                admitted("reserve", Priority::Background, None)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert!(admitted("reserve", Priority::Background, None)
+            .await
+            .is_none());
+        permits.push(
+            admitted("reserve", Priority::Interactive, None)
+                .await
+                .unwrap(),
+        );
+        assert!(admitted("reserve", Priority::Interactive, None)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn waiting_interactive_requests_go_before_background_ones() {
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(
+                admitted("lanes", Priority::Interactive, None)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let background = tokio::spawn(acquire("lanes", Priority::Background, None));
+        let interactive = tokio::spawn(acquire("lanes", Priority::Interactive, None));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        permits.truncate(2);
+        // the interactive request takes a freed slot, and the other one is the reserve
+        let interactive = interactive.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!background.is_finished());
+        drop(interactive);
+        assert!(background.await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_give_up_waiting() {
+        let mut permits = Vec::new();
+        for _ in 0..4 {
+            permits.push(
+                admitted("cancel", Priority::Interactive, None)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let cancel = CancelToken::new();
+        let waiting = {
+            let cancel = cancel.clone();
+            tokio::spawn(
+                async move { acquire("cancel", Priority::Interactive, Some(&cancel)).await },
+            )
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        cancel.cancel();
+        assert!(waiting.await.unwrap().is_none());
+        assert!(admitted("cancel", Priority::Interactive, Some(&cancel))
+            .await
+            .is_none());
+        // the cancelled request no longer holds back the background ones
+        permits.truncate(2);
+        assert!(admitted("cancel", Priority::Background, None)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn waiting_to_retry_frees_the_slot() {
+        let mut permits = Vec::new();
+        for _ in 0..2 {
+            permits.push(admitted("retry", Priority::Background, None).await.unwrap());
+        }
+        let retrying = tokio::spawn(with_slot("retry", Priority::Background, None, async {
+            release_while(tokio::time::sleep(Duration::from_millis(100))).await;
+        }));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // the backoff leaves the last background slot to another request
+        let other = admitted("retry", Priority::Background, None).await.unwrap();
+        drop(other);
+        let (_, permit) = retrying.await.unwrap().unwrap();
+        assert!(permit.is_some());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::llm::{CompletionChunk, CompletionStream, LLMError};
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+
+#[derive(Debug, Clone)]
+pub(crate) struct SseEvent {
+    pub data: String,
+}
+
+struct LineState {
+    bytes: BoxStream<'static, Result<Vec<u8>, reqwest::Error>>,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+// splits a response body into lines as the bytes arrive, without the trailing "\n" or "\r\n"
+pub(crate) fn lines(response: reqwest::Response) -> impl Stream<Item = Result<String, LLMError>> {
+    let state = LineState {
+        bytes: response
+            .bytes_stream()
+            .map(|chunk| chunk.map(|bytes| bytes.to_vec()))
+            .boxed(),
+        buffer: Vec::new(),
+        finished: false,
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(pos) = state.buffer.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = state.buffer.drain(..=pos).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Some((Ok(String::from_utf8_lossy(&line).into_owned()), state));
+            }
+            if state.finished {
+                if state.buffer.is_empty() {
+                    return None;
+                }
+                let line = String::from_utf8_lossy(&state.buffer).into_owned();
+                state.buffer.clear();
+                return Some((Ok(line), state));
+            }
+            match state.bytes.next().await {
+                Some(Ok(chunk)) => state.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    state.finished = true;
+                    state.buffer.clear();
+                    return Some((Err(LLMError::RequestError(e)), state));
+                }
+                None => state.finished = true,
+            }
+        }
+    })
+}
+
+// groups lines into server-sent events, see https://html.spec.whatwg.org/multipage/server-sent-events.html
+pub(crate) fn sse_events(
+    response: reqwest::Response,
+) -> impl Stream<Item = Result<SseEvent, LLMError>> {
+    let lines = lines(response).boxed();
+    stream::unfold((lines, false), |(mut lines, done)| async move {
+        if done {
+            return None;
+        }
+        let mut data: Vec<String> = Vec::new();
+        loop {
+            match lines.next().await {
+                Some(Ok(line)) => {
+                    if line.is_empty() {
+                        if data.is_empty() {
+                            continue;
+                        }
+                        let sse_event = SseEvent {
+                            data: data.join("\n"),
+                        };
+                        return Some((Ok(sse_event), (lines, false)));
+                    }
+                    if line.starts_with(':') {
+                        continue;
+                    }
+                    // the provider payloads repeat the `event` field, so only `data` is kept
+                    if let Some(value) = line.strip_prefix("data:") {
+                        data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), (lines, true))),
+                None => {
+                    if data.is_empty() {
+                        return None;
+                    }
+                    let sse_event = SseEvent {
+                        data: data.join("\n"),
+                    };
+                    return Some((Ok(sse_event), (lines, true)));
+                }
+            }
+        }
+    })
+}
+
+// turns a stream of provider events into completion chunks, threading `state` through `parse`
+// so that providers can combine information that arrives in separate events (e.g. usage)
+pub(crate) fn completion_stream<E, T, S, F>(events: E, state: S, mut parse: F) -> CompletionStream
+where
+    E: Stream<Item = Result<T, LLMError>> + Send + 'static,
+    T: Send + 'static,
+    S: Send + 'static,
+    F: FnMut(&mut S, T) -> Result<Vec<CompletionChunk>, LLMError> + Send + 'static,
+{
+    events
+        .scan(state, move |state, event| {
+            let chunks: Vec<Result<CompletionChunk, LLMError>> = match event {
+                Ok(event) => match parse(state, event) {
+                    Ok(chunks) => chunks.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                },
+                Err(e) => vec![Err(e)],
+            };
+            futures::future::ready(Some(stream::iter(chunks)))
+        })
+        .flatten()
+        .boxed()
+}
+
+pub(crate) fn parse_json_event<T: serde::de::DeserializeOwned>(data: &str) -> Result<T, LLMError> {
+    match serde_json::from_str(data) {
+        Ok(value) => Ok(value),
+        Err(e) => Err(LLMError::ParseError(format!(
+            "Error parsing stream event `{}`: {}",
+            data, e
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    // a response whose body arrives in these chunks
+    fn response(chunks: &[&'static str]) -> reqwest::Response {
+        let chunks: Vec<Result<&'static str, std::io::Error>> =
+            chunks.iter().map(|&chunk| Ok(chunk)).collect();
+        let body = reqwest::Body::wrap_stream(stream::iter(chunks));
+        reqwest::Response::from(http::Response::new(body))
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Event {
+        n: u32,
+    }
+
+    #[tokio::test]
+    async fn sse_events_join_data_lines_split_across_chunks() {
+        let events: Vec<String> = sse_events(response(&[
+            ": keep-alive\n\nevent: delta\ndata: {\"n\"",
+            ": 1}\r\n\r\ndata: first\ndata:second\n",
+            "\nevent: ping\n\ndata: [DONE]",
+        ]))
+        .map(|event| event.unwrap().data)
+        .collect()
+        .await;
+        assert_eq!(events, ["{\"n\": 1}", "first\nsecond", "[DONE]"]);
+        assert_eq!(
+            parse_json_event::<Event>(&events[0]).unwrap(),
+            Event { n: 1 }
+        );
+    }
+
+    #[tokio::test]
+    async fn ndjson_lines_are_parsed_as_they_complete() {
+        let events: Vec<Event> = lines(response(&["{\"n\": 1}\n{\"n\"", ": 2}\r\n\n{\"n\": 3}"]))
+            .filter_map(|line| async move {
+                let line = line.unwrap();
+                (!line.is_empty()).then(|| parse_json_event::<Event>(&line).unwrap())
+            })
+            .collect()
+            .await;
+        assert_eq!(events, [Event { n: 1 }, Event { n: 2 }, Event { n: 3 }]);
+    }
+
+    #[test]
+    fn parse_json_event_reports_the_bad_event() {
+        match parse_json_event::<Event>("{\"n\": \"one\"}") {
+            Err(LLMError::ParseError(message)) => assert!(message.contains("{\"n\": \"one\"}")),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}
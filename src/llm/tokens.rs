@@ -0,0 +1,128 @@
+use crate::llm::catalog::model_info;
+use crate::llm::{ContentBlock, ImageSource, Message, MessageContent, Model, Provider};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use std::io::Cursor;
+
+// rough average for English text and code across the providers' tokenizers
+const CHARS_PER_TOKEN: usize = 4;
+// role markers and message delimiters
+const TOKENS_PER_MESSAGE: u32 = 4;
+
+pub fn estimate_text_tokens(text: &str) -> u32 {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN) as u32
+}
+
+// follows each provider's published image pricing, including the resizing they apply first
+pub fn estimate_image_tokens(provider: &Provider, width: u32, height: u32) -> u32 {
+    if width == 0 || height == 0 {
+        return 0;
+    }
+    match provider {
+        // resized to fit within 1568px on the long edge and about 1.15 megapixels,
+        // then width * height / 750
+        Provider::Anthropic => {
+            let (width, height) = fit_within(width, height, 1568, 1568);
+            let scale = (1_150_000.0 / (width as f64 * height as f64))
+                .sqrt()
+                .min(1.0);
+            let pixels = (width as f64 * scale) * (height as f64 * scale);
+            (pixels / 750.0).ceil() as u32
+        }
+        // 258 tokens for images up to 384px, larger images are cropped into 768px tiles of 258
+        Provider::Google => {
+            if width <= 384 && height <= 384 {
+                return 258;
+            }
+            width.div_ceil(768) * height.div_ceil(768) * 258
+        }
+        // OpenAI's high detail mode: fit within 2048px, scale the short side down to 768px,
+        // then 170 tokens per 512px tile plus 85; also used for the OpenAI-compatible providers
+        _ => {
+            let (width, height) = fit_within(width, height, 2048, 2048);
+            let (width, height) = if width.min(height) > 768 {
+                let scale = 768.0 / width.min(height) as f64;
+                (
+                    (width as f64 * scale).round() as u32,
+                    (height as f64 * scale).round() as u32,
+                )
+            } else {
+                (width, height)
+            };
+            width.div_ceil(512) * height.div_ceil(512) * 170 + 85
+        }
+    }
+}
+
+pub fn estimate_message_tokens(provider: &Provider, message: &Message) -> u32 {
+    let content_tokens = match &message.content {
+        MessageContent::Text(text) => estimate_text_tokens(text),
+        MessageContent::MultiContent(blocks) => blocks
+            .iter()
+            .map(|block| estimate_content_block_tokens(provider, block))
+            .sum(),
+    };
+    content_tokens + TOKENS_PER_MESSAGE
+}
+
+pub fn estimate_messages_tokens(provider: &Provider, messages: &[Message]) -> u32 {
+    messages
+        .iter()
+        .map(|message| estimate_message_tokens(provider, message))
+        .sum()
+}
+
+fn estimate_content_block_tokens(provider: &Provider, block: &ContentBlock) -> u32 {
+    match block {
+        ContentBlock::Text { text } => estimate_text_tokens(text),
+        ContentBlock::Image { source } => match image_dimensions(source) {
+            Some((width, height)) => estimate_image_tokens(provider, width, height),
+            // assume a full HD screenshot when the image cannot be read
+            None => estimate_image_tokens(provider, 1920, 1080),
+        },
+        ContentBlock::ToolUse { name, input, .. } => {
+            estimate_text_tokens(name) + estimate_text_tokens(&input.to_string())
+        }
+        ContentBlock::ToolResult { content, .. } => estimate_text_tokens(content),
+        ContentBlock::Thinking { thinking, .. } => estimate_text_tokens(thinking),
+        ContentBlock::RedactedThinking { data } => estimate_text_tokens(data),
+    }
+}
+
+// reads only the image header, not the pixels
+fn image_dimensions(source: &ImageSource) -> Option<(u32, u32)> {
+    let bytes = BASE64.decode(&source.data).ok()?;
+    image::io::Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+fn fit_within(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let scale = (max_width as f64 / width as f64)
+        .min(max_height as f64 / height as f64)
+        .min(1.0);
+    (
+        (width as f64 * scale).round() as u32,
+        (height as f64 * scale).round() as u32,
+    )
+}
+
+// total tokens (prompt and completion) that the model accepts; local servers and unknown
+// models are often configured with a small context, so stay conservative
+pub fn context_window(provider: &Provider, model: &Model) -> u32 {
+    model_info(provider, model)
+        .map(|info| info.context_window)
+        .unwrap_or(8_192)
+}
+
+// the APIs reject requests with more images than this regardless of the token count
+pub fn max_images_per_request(provider: &Provider) -> usize {
+    match provider {
+        Provider::Anthropic => 100,
+        Provider::OpenAI => 500,
+        Provider::Google => 3_000,
+        Provider::Fireworks => 30,
+        _ => 10,
+    }
+}
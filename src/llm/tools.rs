@@ -0,0 +1,80 @@
+use crate::llm::{
+    CompletionRequest, CompletionResponse, ContentBlock, LLMError, Message, MessageContent, Role,
+    Tool,
+};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[async_trait]
+pub trait ToolHandler: Send + Sync {
+    fn definition(&self) -> Tool;
+
+    // the error is sent back to the model as a failed tool result
+    async fn call(&self, input: serde_json::Value) -> Result<String, String>;
+}
+
+#[derive(Clone, Default)]
+pub struct Toolbox {
+    handlers: HashMap<String, Arc<dyn ToolHandler>>,
+}
+
+impl Toolbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(mut self, handler: Arc<dyn ToolHandler>) -> Self {
+        self.handlers.insert(handler.definition().name, handler);
+        self
+    }
+
+    pub fn tools(&self) -> Vec<Tool> {
+        self.handlers
+            .values()
+            .map(|handler| handler.definition())
+            .collect()
+    }
+
+    // sends the request, runs the tools the model asks for and sends their results back until the
+    // model answers without calling a tool, or gives up after `max_turns` requests
+    pub async fn run(
+        &self,
+        mut request: CompletionRequest,
+        max_turns: usize,
+    ) -> Result<CompletionResponse, LLMError> {
+        request.options.tools.extend(self.tools());
+        for _ in 0..max_turns {
+            let response = request.clone().do_request_full().await?;
+            let tool_uses = response.tool_uses();
+            if tool_uses.is_empty() {
+                return Ok(response);
+            }
+            let mut results = Vec::new();
+            for (id, name, input) in tool_uses {
+                let result = match self.handlers.get(name) {
+                    Some(handler) => handler.call(input.clone()).await,
+                    None => Err(format!("Unknown tool: {}", name)),
+                };
+                let (content, is_error) = match result {
+                    Ok(content) => (content, false),
+                    Err(e) => (e, true),
+                };
+                results.push(ContentBlock::ToolResult {
+                    tool_use_id: id.to_string(),
+                    content,
+                    is_error,
+                });
+            }
+            request.messages.push(Message {
+                role: Role::Assistant,
+                content: MessageContent::MultiContent(response.content.clone()),
+            });
+            request.messages.push(Message {
+                role: Role::User,
+                content: MessageContent::MultiContent(results),
+            });
+        }
+        Err(LLMError::ToolLoopLimitExceeded(max_turns))
+    }
+}
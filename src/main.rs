@@ -1,9 +1,16 @@
 use clap::{Parser, Subcommand};
+use llm::ReasoningEffort;
+use screenshot::ScreenshotFormat;
+use std::path::PathBuf;
 
 pub mod audio;
 pub mod autocomplete;
+pub mod budget;
+pub mod cassette;
 pub mod embeddings;
+pub mod http_client;
 pub mod image_analysis;
+pub mod ledger;
 pub mod llm;
 pub mod prompts;
 pub mod screenshot;
@@ -18,28 +25,100 @@ pub mod utils;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// maximum USD per hour spent on background screenshot analysis
+    #[arg(long, global = true)]
+    hourly_spend_cap: Option<f64>,
+    /// maximum USD per day spent on background screenshot analysis
+    #[arg(long, global = true)]
+    daily_spend_cap: Option<f64>,
+    /// maximum background screenshot analysis requests per hour
+    #[arg(long, global = true)]
+    hourly_request_cap: Option<usize>,
+    /// maximum background screenshot analysis requests per day
+    #[arg(long, global = true)]
+    daily_request_cap: Option<usize>,
+    /// records every API request and response to this directory
+    #[arg(long, global = true, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// serves the API responses recorded in this directory instead of using the network
+    #[arg(long, global = true)]
+    replay: Option<PathBuf>,
+    /// encoding of the screenshots sent to the models; png and webp keep small text sharp
+    #[arg(long, global = true, value_enum, default_value_t = ScreenshotFormat::Jpeg)]
+    screenshot_format: ScreenshotFormat,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Shell {},
+    Shell {
+        /// writes the session's API usage and cost on exit, as JSON for a `.json` path and CSV otherwise
+        #[arg(long)]
+        usage_report: Option<PathBuf>,
+        /// lets the assistant think for up to this many tokens before answering (Claude 3.7 Sonnet)
+        #[arg(long)]
+        thinking_budget: Option<u32>,
+        /// prints the assistant's thinking before its answers
+        #[arg(long, requires = "thinking_budget")]
+        show_thinking: bool,
+        /// answers with OpenAI's o1 reasoning model, thinking this hard before each answer
+        #[arg(long, value_enum, conflicts_with = "thinking_budget")]
+        reasoning_effort: Option<ReasoningEffort>,
+    },
     Autocomplete {},
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let anthropic_key = std::env::var("ANTHROPIC_API_KEY");
-    let openai_key = std::env::var("OPENAI_API_KEY");
-    if anthropic_key.is_err() {
-        return Err("ANTHROPIC_API_KEY is not set".into());
-    }
-    if openai_key.is_err() {
-        return Err("OPENAI_API_KEY is not set".into());
+    let cli = Cli::parse();
+    // replayed sessions never reach the APIs, so they do not need the keys
+    if cli.replay.is_none() {
+        let anthropic_key = std::env::var("ANTHROPIC_API_KEY");
+        let openai_key = std::env::var("OPENAI_API_KEY").or(std::env::var("AZURE_OPENAI_API_KEY"));
+        if anthropic_key.is_err() {
+            return Err("ANTHROPIC_API_KEY is not set".into());
+        }
+        if openai_key.is_err() {
+            return Err("OPENAI_API_KEY (or AZURE_OPENAI_API_KEY) is not set".into());
+        }
     }
 
-    let cli = Cli::parse();
+    let cassette = match (cli.record, cli.replay) {
+        (Some(dir), _) => Some(cassette::CassetteConfig {
+            dir,
+            mode: cassette::CassetteMode::Record,
+        }),
+        (None, Some(dir)) => Some(cassette::CassetteConfig {
+            dir,
+            mode: cassette::CassetteMode::Replay,
+        }),
+        (None, None) => None,
+    };
+    let _ = http_client::configure(http_client::HttpConfig {
+        cassette,
+        ..Default::default()
+    });
+    let _ = budget::configure(budget::SpendCaps {
+        hourly_spend_usd: cli.hourly_spend_cap,
+        daily_spend_usd: cli.daily_spend_cap,
+        hourly_requests: cli.hourly_request_cap,
+        daily_requests: cli.daily_request_cap,
+    });
+    let _ = screenshot::configure(cli.screenshot_format);
     match cli.command {
-        Commands::Shell {} => shell::run_shell().await,
+        Commands::Shell {
+            usage_report,
+            thinking_budget,
+            show_thinking,
+            reasoning_effort,
+        } => {
+            shell::run_shell(
+                usage_report,
+                thinking_budget,
+                show_thinking,
+                reasoning_effort,
+            )
+            .await
+        }
         Commands::Autocomplete {} => autocomplete::run_autocomplete().await,
     }
 }
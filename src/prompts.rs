@@ -42,12 +42,10 @@ Based on the screenshots of their work history, predict what they're likely tryi
 This is an autocomplete tool.
 
 ## Format
-Put the text to autocomplete in a markdown code block in the following format:
-```
+Respond with a JSON object in the following format:
 {
     "autocomplete": "<text here>"
-}
-```"#;
+}"#;
 
 pub const DISCARD_REDUNDANT_SCREENSHOT_SYSTEM_PROMPT: &str = r#"# Task
 You will be given two screenshots.
@@ -60,21 +58,8 @@ Examples of redundant screenshot scenarios include:
 - The user is scrolling through a webpage but the missing content from the previous screenshot is not important or was only whitespace, so discarding the previous screenshot would not be detrimental to the history.
 
 ## Format
-First, write a reasoning trace that analyzes both screenshots and determines if the previous screenshot contains any important information that is not present in the current screenshot.
-Then, write a JSON object in a markdown code block with the following format:
-
-```json
-{{
-    "previous_screenshot_contains_important_information_not_present_in_current_screenshot": boolean
-}}
-
-For example:
-
-<your reasoning trace>
-
-```json
-{{
+Respond with a JSON object in the following format:
+{
+    "reasoning": "<a reasoning trace that analyzes both screenshots and determines if the previous screenshot contains any important information that is not present in the current screenshot>",
     "previous_screenshot_contains_important_information_not_present_in_current_screenshot": <true or false>
-}}
-```
-"#;
+}"#;
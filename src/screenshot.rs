@@ -1,3 +1,7 @@
+use crate::cassette::{self, CassetteMode, RecordedScreenshot};
+use crate::http_client;
+use crate::ledger::Purpose;
+use crate::llm::scheduler::CancelToken;
 use crate::llm::{
     CompletionBuilder, ContentBlock, ImageSource, LLMError, Message, MessageContent, Model,
     Provider, Role,
@@ -5,20 +9,60 @@ use crate::llm::{
 use crate::prompts::SCREENSHOT_DESCRIPTION_SYSTEM_PROMPT;
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use chrono::{DateTime, Utc};
-use image::{ImageBuffer, Rgba};
+use image::{ColorType, ImageBuffer, ImageEncoder, Rgba};
 use screenshots::Screen;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
 use std::time::SystemTime;
 use thiserror::Error;
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ScreenshotFormat {
+    // lossy and the smallest, but blurs small text
+    #[default]
+    Jpeg,
+    // lossless, keeps text-heavy screens sharp
+    Png,
+    // lossless, smaller than PNG
+    Webp,
+}
+
+impl ScreenshotFormat {
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            ScreenshotFormat::Jpeg => "image/jpeg",
+            ScreenshotFormat::Png => "image/png",
+            ScreenshotFormat::Webp => "image/webp",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Screenshot {
     pub timestamp: SystemTime,
     // base64 encoded image data
     pub image_data: String,
+    pub format: ScreenshotFormat,
     pub image: ImageBuffer<Rgba<u8>, Vec<u8>>,
 }
 
+static FORMAT: OnceLock<ScreenshotFormat> = OnceLock::new();
+
+// must be called before the first screenshot, returns the format back if it is too late
+pub fn configure(format: ScreenshotFormat) -> Result<(), ScreenshotFormat> {
+    FORMAT.set(format)
+}
+
 impl Screenshot {
+    pub fn image_source(&self) -> ImageSource {
+        ImageSource {
+            source_type: "base64".to_string(),
+            media_type: self.format.media_type().to_string(),
+            data: self.image_data.clone(),
+        }
+    }
+
     pub fn to_llm_message(&self, suffix: Option<String>) -> Message {
         let datetime: DateTime<Utc> = self.timestamp.into();
         let formatted_datetime = datetime.format("%d/%m/%Y %T");
@@ -30,11 +74,7 @@ impl Screenshot {
             role: Role::User,
             content: MessageContent::MultiContent(vec![
                 ContentBlock::Image {
-                    source: ImageSource {
-                        source_type: "base64".to_string(),
-                        media_type: "image/jpeg".to_string(),
-                        data: self.image_data.clone(),
-                    },
+                    source: self.image_source(),
                 },
                 ContentBlock::Text {
                     text: format!("[Screenshot taken at {}]{}", formatted_datetime, suffix),
@@ -52,19 +92,64 @@ pub enum ScreenshotError {
     EncodeError,
     #[error("No screens found")]
     NoScreensFound,
+    #[error("No recorded screenshot left to replay")]
+    NoRecordedScreenshot,
 }
 
+// records or replays the screenshot when a cassette is in use
 pub async fn take_screenshot() -> Result<Screenshot, ScreenshotError> {
+    let cassette_config = match http_client::cassette() {
+        Some(cassette_config) => cassette_config,
+        None => return capture_screenshot(),
+    };
+    match cassette_config.mode {
+        CassetteMode::Record => {
+            let screenshot = capture_screenshot()?;
+            cassette::record_screenshot(
+                cassette_config,
+                &RecordedScreenshot {
+                    timestamp: screenshot.timestamp,
+                    format: screenshot.format,
+                    image_data: screenshot.image_data.clone(),
+                },
+            );
+            Ok(screenshot)
+        }
+        CassetteMode::Replay => match cassette::replay_screenshot(cassette_config) {
+            Some(recorded) => from_recording(recorded),
+            None => Err(ScreenshotError::NoRecordedScreenshot),
+        },
+    }
+}
+
+fn from_recording(recorded: RecordedScreenshot) -> Result<Screenshot, ScreenshotError> {
+    let bytes = match BASE64.decode(&recorded.image_data) {
+        Ok(bytes) => bytes,
+        Err(_) => return Err(ScreenshotError::CaptureError),
+    };
+    let image = match image::load_from_memory(&bytes) {
+        Ok(image) => image.to_rgba8(),
+        Err(_) => return Err(ScreenshotError::CaptureError),
+    };
+    Ok(Screenshot {
+        timestamp: recorded.timestamp,
+        image_data: recorded.image_data,
+        format: recorded.format,
+        image,
+    })
+}
+
+fn capture_screenshot() -> Result<Screenshot, ScreenshotError> {
     // for now, just take the first screen
     if let Some(screen) = Screen::all().unwrap().first() {
         if let Ok(image) = screen.capture() {
-            let mut buffer = Vec::new();
-            let mut encoder = image::codecs::jpeg::JpegEncoder::new(&mut buffer);
-            if encoder.encode_image(&image).is_ok() {
+            let format = *FORMAT.get_or_init(ScreenshotFormat::default);
+            if let Some(buffer) = encode(&image, format) {
                 let base64 = BASE64.encode(&buffer);
                 Ok(Screenshot {
                     timestamp: SystemTime::now(),
                     image_data: base64,
+                    format,
                     image,
                 })
             } else {
@@ -81,6 +166,9 @@ pub async fn take_screenshot() -> Result<Screenshot, ScreenshotError> {
 pub async fn generate_text_description_of_screenshot(
     screenshot: &Screenshot,
     conversation_history: &[Message],
+    provider: Provider,
+    model: Model,
+    cancel: CancelToken,
 ) -> Result<String, LLMError> {
     let mut messages = vec![Message {
         role: Role::System,
@@ -93,11 +181,7 @@ pub async fn generate_text_description_of_screenshot(
         role: Role::User,
         content: MessageContent::MultiContent(vec![
             ContentBlock::Image {
-                source: ImageSource {
-                    source_type: "base64".to_string(),
-                    media_type: "image/jpeg".to_string(),
-                    data: screenshot.image_data.clone(),
-                },
+                source: screenshot.image_source(),
             },
             ContentBlock::Text {
                 text: "Write a text description of this screenshot.".to_string(),
@@ -105,10 +189,32 @@ pub async fn generate_text_description_of_screenshot(
         ]),
     });
     let completion_request = CompletionBuilder::new()
-        .model(Model::GPT4oMini)
-        .provider(Provider::OpenAI)
+        .model(model)
+        .provider(provider)
         .messages(messages)
+        .purpose(Purpose::Description)
+        .cache(true)
         .temperature(0.0)
+        .cancel_token(cancel)
         .build();
     completion_request.do_request().await
 }
+
+fn encode(image: &ImageBuffer<Rgba<u8>, Vec<u8>>, format: ScreenshotFormat) -> Option<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let (width, height) = image.dimensions();
+    let result = match format {
+        ScreenshotFormat::Jpeg => {
+            image::codecs::jpeg::JpegEncoder::new(&mut buffer).encode_image(image)
+        }
+        ScreenshotFormat::Png => image::codecs::png::PngEncoder::new(&mut buffer).write_image(
+            image,
+            width,
+            height,
+            ColorType::Rgba8,
+        ),
+        ScreenshotFormat::Webp => image::codecs::webp::WebPEncoder::new_lossless(&mut buffer)
+            .write_image(image, width, height, ColorType::Rgba8),
+    };
+    result.ok().map(|_| buffer)
+}
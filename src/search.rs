@@ -1,11 +1,14 @@
-use crate::embeddings::embedding;
+use crate::embeddings::{embedding, EmbeddingError};
+use crate::ledger::Purpose;
 use std::collections::BinaryHeap;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum SearchError {
     #[error("Error embedding query")]
-    EmbeddingError(#[from] reqwest::Error),
+    EmbeddingError(#[from] EmbeddingError),
+    #[error("No embedding returned for the query")]
+    EmptyEmbedding,
 }
 
 #[derive(Debug, Clone)]
@@ -22,13 +25,13 @@ pub struct DenseEmbeddingSearchResult<'a, T> {
 
 impl<'a, T> PartialOrd for DenseEmbeddingSearchResult<'a, T> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.distance.partial_cmp(&other.distance)
+        Some(self.cmp(other))
     }
 }
 
 impl<'a, T> Ord for DenseEmbeddingSearchResult<'a, T> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.distance.partial_cmp(&other.distance).unwrap()
     }
 }
 
@@ -44,15 +47,22 @@ pub async fn dense_embedding_search<'a, T>(
     query: &str,
     embedded_documents: &'a [EmbeddedDocument<'a, T>],
     max_results: usize,
+    purpose: Purpose,
 ) -> Result<Vec<DenseEmbeddingSearchResult<'a, T>>, SearchError> {
-    let query_embedding_result = embedding(vec![query.to_string()]).await.unwrap();
-    let query_embedding = query_embedding_result.first().unwrap();
+    let query_embedding_result = match embedding(vec![query.to_string()], purpose).await {
+        Ok(result) => result,
+        Err(e) => return Err(SearchError::EmbeddingError(e)),
+    };
+    let query_embedding = match query_embedding_result.embeddings.first() {
+        Some(query_embedding) => query_embedding,
+        None => return Err(SearchError::EmptyEmbedding),
+    };
     let mut heap: BinaryHeap<DenseEmbeddingSearchResult<'a, T>> =
         BinaryHeap::with_capacity(max_results);
     for embedded_document in embedded_documents {
-        let distance = cosine_distance(&query_embedding, &embedded_document.embedding);
+        let distance = cosine_distance(query_embedding, embedded_document.embedding);
         heap.push(DenseEmbeddingSearchResult {
-            embedded_document: embedded_document,
+            embedded_document,
             distance,
         });
     }
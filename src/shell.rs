@@ -1,12 +1,53 @@
-use crate::llm::{CompletionBuilder, Model, Provider};
+use crate::budget;
+use crate::ledger::{self, Purpose};
+use crate::llm::{
+    cache, CompletionBuilder, CompletionChunk, CompletionStream, LLMError, Model, Provider,
+    ReasoningEffort,
+};
 use crate::screenshot::take_screenshot;
 use crate::trajectory::Trajectory;
+use futures::StreamExt;
 use std::io::{self, Write};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
-pub async fn run_shell() -> Result<(), Box<dyn std::error::Error>> {
-    let trajectory = Arc::new(Mutex::new(Trajectory::new(true)));
+pub async fn run_shell(
+    usage_report: Option<PathBuf>,
+    thinking_budget: Option<u32>,
+    show_thinking: bool,
+    reasoning_effort: Option<ReasoningEffort>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // extended thinking needs Claude 3.7 Sonnet, and a reasoning effort OpenAI's o1
+    let (provider, model, fallbacks) = match (reasoning_effort, thinking_budget) {
+        (Some(_), _) => (
+            Provider::OpenAI,
+            Model::O1,
+            vec![
+                (Provider::Anthropic, Model::Claude35Sonnet),
+                (Provider::Google, Model::Gemini15Pro),
+            ],
+        ),
+        (None, Some(_)) => (
+            Provider::Anthropic,
+            Model::Claude37Sonnet,
+            vec![
+                (Provider::OpenAI, Model::GPT4o),
+                (Provider::Google, Model::Gemini15Pro),
+            ],
+        ),
+        (None, None) => (
+            Provider::Anthropic,
+            Model::Claude35Sonnet,
+            vec![
+                (Provider::OpenAI, Model::GPT4o),
+                (Provider::Google, Model::Gemini15Pro),
+            ],
+        ),
+    };
+    let trajectory = Arc::new(Mutex::new(
+        Trajectory::new(true).with_model(provider.clone(), model.clone()),
+    ));
     let trajectory_clone = trajectory.clone();
     let screenshot_task_handle = tokio::spawn(async move {
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
@@ -35,6 +76,9 @@ pub async fn run_shell() -> Result<(), Box<dyn std::error::Error>> {
     send_message_to_stdout("assistant", greeting);
 
     loop {
+        for warning in budget::take_warnings() {
+            println!("[warning] {}", warning);
+        }
         print!("user: ");
         match io::stdout().flush() {
             Ok(_) => (),
@@ -71,27 +115,59 @@ pub async fn run_shell() -> Result<(), Box<dyn std::error::Error>> {
             .add_user_message(input.to_string())
             .await;
 
-        let messages = match trajectory.lock().await.build_messages(Some(input)).await {
-            Ok(messages) => messages,
+        let built = match trajectory
+            .lock()
+            .await
+            .build_request_messages(Some(input))
+            .await
+        {
+            Ok(built) => built,
             Err(e) => {
                 println!("Error: {}", e);
                 continue;
             }
         };
-        let completion_request = CompletionBuilder::new()
-            .model(Model::Claude35Sonnet)
-            .provider(Provider::Anthropic)
-            .messages(messages)
-            .temperature(0.7)
-            .build();
-
-        let response = match completion_request.do_request().await {
-            Ok(response) => response,
+        let mut completion_builder = CompletionBuilder::new()
+            .model(model.clone())
+            .provider(provider.clone())
+            .fallbacks(fallbacks.clone())
+            .messages(built.messages)
+            .cache_breakpoints(built.cache_breakpoints)
+            .image_descriptions(built.image_descriptions)
+            .image_drop_order(built.image_drop_order)
+            .purpose(Purpose::Chat)
+            .temperature(0.7);
+        if let Some(budget_tokens) = thinking_budget {
+            completion_builder = completion_builder.thinking(budget_tokens);
+        }
+        if let Some(reasoning_effort) = reasoning_effort {
+            completion_builder = completion_builder.reasoning_effort(reasoning_effort);
+        }
+        let completion_request = completion_builder.build();
+
+        let stream_response = match completion_request.do_request_stream_full().await {
+            Ok(stream_response) => stream_response,
+            Err(e) if is_fatal(&e) => return Err(e.into()),
             Err(e) => {
-                return Err(e.into());
+                report_error(&e);
+                continue;
             }
         };
-        send_message_to_stdout("assistant", &response);
+        if stream_response.provider != provider {
+            println!(
+                "[warning] {} is unavailable, answering with {} ({})",
+                provider, stream_response.model, stream_response.provider
+            );
+        }
+        let response =
+            match send_stream_to_stdout("assistant", stream_response.stream, show_thinking).await {
+                Ok(response) => response,
+                Err(e) if is_fatal(&e) => return Err(e.into()),
+                Err(e) => {
+                    report_error(&e);
+                    continue;
+                }
+            };
         trajectory
             .lock()
             .await
@@ -99,9 +175,144 @@ pub async fn run_shell() -> Result<(), Box<dyn std::error::Error>> {
             .await;
     }
     screenshot_task_handle.abort();
+    println!("\nAPI usage this session:\n{}", ledger::summary());
+    let cache_stats = cache::stats();
+    if cache_stats.hits + cache_stats.misses > 0 {
+        println!(
+            "Response cache: {} hits, {} misses, {} evictions",
+            cache_stats.hits, cache_stats.misses, cache_stats.evictions
+        );
+    }
+    if let Some(path) = usage_report {
+        match ledger::export(&path) {
+            Ok(_) => println!("Usage report written to {}", path.display()),
+            Err(e) => println!("Error: {}", e),
+        }
+    }
     Ok(())
 }
 
+// every provider in the fallback chain rejected the credentials or is out of quota, so no later
+// message can succeed
+fn is_fatal(e: &LLMError) -> bool {
+    e.candidate_errors()
+        .iter()
+        .all(|e| matches!(e, LLMError::Authentication(_) | LLMError::QuotaExceeded(_)))
+}
+
+// the message the user typed stays in the trajectory, so they can simply ask again
+fn report_error(e: &LLMError) {
+    println!("Error: {}", e);
+    for e in e.candidate_errors() {
+        if let Some(hint) = error_hint(e) {
+            println!("[warning] {}", hint);
+        }
+    }
+}
+
+fn error_hint(e: &LLMError) -> Option<String> {
+    let hint = match e {
+        LLMError::RateLimited(error) => match error.retry_after {
+            Some(retry_after) => format!(
+                "{} is rate limiting requests, try again in {} seconds",
+                error.provider,
+                retry_after.as_secs()
+            ),
+            None => format!(
+                "{} is rate limiting requests, try again shortly",
+                error.provider
+            ),
+        },
+        LLMError::Overloaded(error) | LLMError::ServerError(error) => format!(
+            "{} is having trouble right now, try again in a moment",
+            error.provider
+        ),
+        LLMError::ContextLengthExceeded(_) => {
+            "The conversation no longer fits in the model's context window, even without screenshots"
+                .to_string()
+        }
+        LLMError::ContentPolicy(error) => {
+            format!("{} declined the request, try rephrasing it", error.provider)
+        }
+        _ => return None,
+    };
+    Some(hint)
+}
+
 fn send_message_to_stdout(author: &str, message: &str) {
     println!("{}: {}", author, message);
 }
+
+// prints the tokens as they arrive and returns the full message, without the thinking
+async fn send_stream_to_stdout(
+    author: &str,
+    mut stream: CompletionStream,
+    show_thinking: bool,
+) -> Result<String, LLMError> {
+    let mut message = String::new();
+    let mut thinking = false;
+    print!("{}: ", author);
+    let _ = io::stdout().flush();
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(CompletionChunk::ThinkingDelta(delta)) => {
+                if show_thinking {
+                    if !thinking {
+                        print!("\n[thinking] ");
+                        thinking = true;
+                    }
+                    print!("{}", delta);
+                    let _ = io::stdout().flush();
+                }
+            }
+            Ok(CompletionChunk::Delta(delta)) => {
+                if thinking {
+                    print!("\n{}: ", author);
+                    thinking = false;
+                }
+                print!("{}", delta);
+                let _ = io::stdout().flush();
+                message.push_str(&delta);
+            }
+            Ok(CompletionChunk::Usage(_)) => (),
+            Err(e) => {
+                println!();
+                return Err(e);
+            }
+        }
+    }
+    println!();
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ProviderError;
+
+    #[test]
+    fn mixed_candidate_errors_are_not_fatal() {
+        let e = LLMError::AllCandidatesFailed(vec![
+            LLMError::Authentication(ProviderError::fixture("anthropic", 401)),
+            LLMError::RateLimited(ProviderError::fixture("openai", 429)),
+        ]);
+        assert!(!is_fatal(&e));
+    }
+
+    #[test]
+    fn credential_and_quota_errors_of_every_candidate_are_fatal() {
+        let e = LLMError::AllCandidatesFailed(vec![
+            LLMError::Authentication(ProviderError::fixture("anthropic", 401)),
+            LLMError::QuotaExceeded(ProviderError::fixture("openai", 402)),
+        ]);
+        assert!(is_fatal(&e));
+        assert!(is_fatal(&LLMError::Authentication(ProviderError::fixture(
+            "anthropic",
+            401
+        ))));
+        assert!(!is_fatal(&LLMError::Overloaded(ProviderError::fixture(
+            "anthropic",
+            529
+        ))));
+    }
+}
@@ -1,24 +1,42 @@
+use crate::budget::background_mode;
 use crate::embeddings::embedding;
 use crate::image_analysis::is_redundant_screenshot;
-use crate::llm::{Message, MessageContent, Role};
+use crate::ledger::Purpose;
+use crate::llm::scheduler::CancelToken;
+use crate::llm::tokens::{
+    context_window, estimate_image_tokens, estimate_message_tokens, max_images_per_request,
+};
+use crate::llm::{LLMError, Message, MessageContent, Model, Provider, Role};
 use crate::screenshot::{generate_text_description_of_screenshot, Screenshot};
 use crate::search::{dense_embedding_search, EmbeddedDocument, SearchError};
+use std::collections::HashMap;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::Mutex;
 
-// save 5000 tokens for the conversation
-// 80 images * 1600 tokens per image = 128000 tokens
-// total = 5000 + 128000 = 133000 tokens
-const MAX_NUM_IMAGES_PER_LLM_CALL: usize = 80;
-const MAX_NUM_EXPLICIT_RECENT_IMAGES_PER_LLM_CALL: usize = 40;
-const MAX_NUM_RETRIEVED_IMAGES_PER_LLM_CALL: usize =
-    MAX_NUM_IMAGES_PER_LLM_CALL - MAX_NUM_EXPLICIT_RECENT_IMAGES_PER_LLM_CALL;
+// room for the system prompt and anything else the caller adds to the built messages
+const CONVERSATION_TOKEN_RESERVE: u32 = 5000;
+const COMPLETION_TOKEN_RESERVE: u32 = 8192;
+// the "[Screenshot taken at ...]" caption and message delimiters
+const SCREENSHOT_CAPTION_TOKENS: u32 = 20;
+// screenshots leave the recent window in groups of this many, and every this many screenshots
+// ends a cached prefix
+const CACHE_WINDOW_STEP: usize = 8;
+// Anthropic allows four cache breakpoints per request
+const MAX_CACHE_BREAKPOINTS: usize = 4;
 
 #[derive(Debug, Clone)]
 pub struct Trajectory {
     events: Arc<Mutex<Vec<Event>>>,
     discard_redundant_screenshots: bool,
+    // the model the built messages are sent to, which sets the token budget
+    provider: Provider,
+    model: Model,
+    // used for the background screenshot descriptions and redundancy checks
+    screenshot_analysis_provider: Provider,
+    screenshot_analysis_model: Model,
+    // screenshots added so far, used to throttle the descriptions
+    num_frames: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +45,8 @@ pub struct ScreenshotEvent {
     pub screenshot: Screenshot,
     pub is_redundant: bool,
     pub text_embedding: Option<Vec<f32>>,
+    // cancels the description and embedding once the screenshot turns out to be redundant
+    pub background_jobs: CancelToken,
 }
 
 #[derive(Debug, Clone)]
@@ -35,6 +55,16 @@ pub enum Event {
     Screenshot(ScreenshotEvent),
 }
 
+// the messages for a request, with the indexes to pass to the `CompletionBuilder` methods of the
+// same names
+#[derive(Debug, Clone)]
+pub struct BuiltMessages {
+    pub messages: Vec<Message>,
+    pub cache_breakpoints: Vec<usize>,
+    pub image_descriptions: HashMap<usize, String>,
+    pub image_drop_order: Vec<usize>,
+}
+
 #[derive(Error, Debug)]
 pub enum BuildMessagesError {
     #[error("Error retrieving images")]
@@ -46,9 +76,26 @@ impl Trajectory {
         Self {
             events: Arc::new(Mutex::new(Vec::new())),
             discard_redundant_screenshots,
+            provider: Provider::Anthropic,
+            model: Model::Claude35Sonnet,
+            screenshot_analysis_provider: Provider::OpenAI,
+            screenshot_analysis_model: Model::GPT4oMini,
+            num_frames: 0,
         }
     }
 
+    pub fn with_model(mut self, provider: Provider, model: Model) -> Self {
+        self.provider = provider;
+        self.model = model;
+        self
+    }
+
+    pub fn with_screenshot_analysis_model(mut self, provider: Provider, model: Model) -> Self {
+        self.screenshot_analysis_provider = provider;
+        self.screenshot_analysis_model = model;
+        self
+    }
+
     pub async fn add_event(&mut self, event: Event) {
         self.events.lock().await.push(event);
     }
@@ -89,16 +136,24 @@ impl Trajectory {
             }
         }
         let events = self.events.clone();
+        let background_jobs = CancelToken::new();
         events.lock().await.push(Event::Screenshot(ScreenshotEvent {
             text_description: None,
             screenshot: screenshot.clone(),
             is_redundant: false,
             text_embedding: None,
+            background_jobs: background_jobs.clone(),
         }));
         let new_event_idx = events.lock().await.len() - 1;
-        if self.discard_redundant_screenshots {
+        // the background calls are scaled back as their spend approaches the caps
+        let mode = background_mode();
+        let frame = self.num_frames;
+        self.num_frames += 1;
+        if self.discard_redundant_screenshots && mode.redundancy_checks_enabled() {
             let events = events.clone();
             let screenshot = screenshot.clone();
+            let provider = self.screenshot_analysis_provider.clone();
+            let model = self.screenshot_analysis_model.clone();
             tokio::spawn(async move {
                 let last_screenshot = match events.lock().await.get(new_event_idx - 1) {
                     Some(Event::Screenshot(screenshot_event)) => {
@@ -107,7 +162,9 @@ impl Trajectory {
                     _ => return,
                 };
                 let should_discard_previous_screenshot =
-                    match is_redundant_screenshot(&last_screenshot, &screenshot).await {
+                    match is_redundant_screenshot(&last_screenshot, &screenshot, provider, model)
+                        .await
+                    {
                         Ok(should_discard_previous_screenshot) => {
                             should_discard_previous_screenshot
                         }
@@ -121,38 +178,69 @@ impl Trajectory {
                         &mut events.lock().await[new_event_idx]
                     {
                         screenshot_event.is_redundant = true;
+                        screenshot_event.background_jobs.cancel();
                     }
                 }
             });
         }
-        let conversation_history = self
-            .build_messages(None)
+        if !mode.should_describe(frame) {
+            return;
+        }
+        let provider = self.screenshot_analysis_provider.clone();
+        let model = self.screenshot_analysis_model.clone();
+        // the new screenshot is sent after the history, so one image is left for it
+        let conversation_history = match self
+            .build_request_messages_for(&provider, &model, None, 1)
             .await
-            .unwrap()
-            .into_iter()
-            .filter(|message| message.role != Role::System)
-            .collect::<Vec<Message>>();
+        {
+            Ok(built) => built
+                .messages
+                .into_iter()
+                .filter(|message| message.role != Role::System)
+                .collect::<Vec<Message>>(),
+            Err(e) => {
+                println!(
+                    "[warning] Error building the screenshot description history: {}",
+                    e
+                );
+                return;
+            }
+        };
         tokio::spawn(async move {
-            let text_description =
-                generate_text_description_of_screenshot(&screenshot, &conversation_history).await;
+            let text_description = generate_text_description_of_screenshot(
+                &screenshot,
+                &conversation_history,
+                provider,
+                model,
+                background_jobs.clone(),
+            )
+            .await;
             match text_description {
                 Ok(text_description) => {
-                    let mut events = events.lock().await;
-                    if let Event::Screenshot(screenshot_event) = &mut events[new_event_idx] {
+                    if let Event::Screenshot(screenshot_event) =
+                        &mut events.lock().await[new_event_idx]
+                    {
                         screenshot_event.text_description = Some(text_description.clone());
                     }
-                    let text_embedding = match embedding(vec![text_description]).await {
-                        Ok(text_embedding) => text_embedding,
-                        Err(e) => {
-                            println!("[warning] Error generating text embedding: {}", e);
-                            return;
-                        }
-                    };
-                    if let Event::Screenshot(screenshot_event) = &mut events[new_event_idx] {
+                    if background_jobs.is_cancelled() {
+                        return;
+                    }
+                    let text_embedding =
+                        match embedding(vec![text_description], Purpose::Description).await {
+                            Ok(text_embedding) => text_embedding,
+                            Err(e) => {
+                                println!("[warning] Error generating text embedding: {}", e);
+                                return;
+                            }
+                        };
+                    if let Event::Screenshot(screenshot_event) =
+                        &mut events.lock().await[new_event_idx]
+                    {
                         screenshot_event.text_embedding =
-                            Some(text_embedding.first().unwrap().clone());
+                            Some(text_embedding.embeddings.first().unwrap().clone());
                     }
                 }
+                Err(LLMError::Cancelled) => (),
                 Err(e) => {
                     println!(
                         "[warning] Error generating text description of screenshot: {}",
@@ -163,73 +251,226 @@ impl Trajectory {
         });
     }
 
+    // keeps every message, then fills the model's context window with the most recent
+    // screenshots and, given a query, the older screenshots that are most relevant to it
     pub async fn build_messages(
         &self,
         query_for_retrieval: Option<&str>,
     ) -> Result<Vec<Message>, BuildMessagesError> {
-        let mut messages_rev = Vec::new();
-        let mut num_explicit_recent_images = 0;
-        let mut retrieval_corpus_screenshot_idxs: Vec<usize> = Vec::new();
-        for (idx, event) in self
-            .events
-            .lock()
+        match self
+            .build_messages_with_cache_breakpoints(query_for_retrieval)
             .await
-            .clone()
-            .into_iter()
-            .rev()
-            .enumerate()
         {
-            match event {
-                Event::Message(message) => messages_rev.push(message),
-                Event::Screenshot(screenshot_event) => {
-                    if screenshot_event.is_redundant {
-                        continue;
-                    }
-                    if num_explicit_recent_images < MAX_NUM_EXPLICIT_RECENT_IMAGES_PER_LLM_CALL {
-                        messages_rev.push(screenshot_event.screenshot.to_llm_message(None));
-                        num_explicit_recent_images += 1;
-                    } else if query_for_retrieval.is_some() {
-                        retrieval_corpus_screenshot_idxs.push(idx);
-                    }
-                }
-            }
+            Ok((messages, _)) => Ok(messages),
+            Err(e) => Err(e),
         }
-        let mut retrieval_corpus: Vec<EmbeddedDocument<Screenshot>> = Vec::new();
+    }
+
+    // also returns the indexes of the messages that end a prefix the next call is likely to
+    // repeat, for `CompletionBuilder::cache_breakpoints`
+    pub async fn build_messages_with_cache_breakpoints(
+        &self,
+        query_for_retrieval: Option<&str>,
+    ) -> Result<(Vec<Message>, Vec<usize>), BuildMessagesError> {
+        match self.build_request_messages(query_for_retrieval).await {
+            Ok(built) => Ok((built.messages, built.cache_breakpoints)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // also returns what the completion layer needs to shrink the request if it still does not
+    // fit: the retrieved screenshots go first, then the oldest recent ones
+    pub async fn build_request_messages(
+        &self,
+        query_for_retrieval: Option<&str>,
+    ) -> Result<BuiltMessages, BuildMessagesError> {
+        self.build_request_messages_for(&self.provider, &self.model, query_for_retrieval, 0)
+            .await
+    }
+
+    // budgets the images and tokens for `model` of `provider`, keeping `reserved_images` of its
+    // images per request for the caller
+    async fn build_request_messages_for(
+        &self,
+        provider: &Provider,
+        model: &Model,
+        query_for_retrieval: Option<&str>,
+        reserved_images: usize,
+    ) -> Result<BuiltMessages, BuildMessagesError> {
         let events = self.events.lock().await.clone();
-        for idx in retrieval_corpus_screenshot_idxs {
-            if let Event::Screenshot(screenshot_event) = &events[idx] {
-                retrieval_corpus.push(EmbeddedDocument {
-                    document: screenshot_event.screenshot.clone(),
-                    embedding: &screenshot_event.text_embedding.as_ref().unwrap(),
-                });
+        let mut included = vec![false; events.len()];
+        let mut message_tokens = 0;
+        for (idx, event) in events.iter().enumerate() {
+            if let Event::Message(message) = event {
+                included[idx] = true;
+                message_tokens += estimate_message_tokens(provider, message);
             }
         }
+        let image_budget = context_window(provider, model)
+            .saturating_sub(CONVERSATION_TOKEN_RESERVE + COMPLETION_TOKEN_RESERVE + message_tokens);
+        // with a query, half of the budget is left for older screenshots that are relevant to it
+        let recent_image_budget = match query_for_retrieval {
+            Some(_) => image_budget / 2,
+            None => image_budget,
+        };
+        let max_images = max_images_per_request(provider).saturating_sub(reserved_images);
+        let mut image_tokens = 0;
+        let mut recent: Vec<(usize, u32)> = Vec::new();
+        let mut retrieval_candidates: Vec<(usize, u32)> = Vec::new();
+        for (idx, event) in events.iter().enumerate().rev() {
+            let screenshot_event = match event {
+                Event::Screenshot(screenshot_event) if !screenshot_event.is_redundant => {
+                    screenshot_event
+                }
+                _ => continue,
+            };
+            let tokens = screenshot_tokens(provider, &screenshot_event.screenshot);
+            if retrieval_candidates.is_empty()
+                && recent.len() < max_images
+                && image_tokens + tokens <= recent_image_budget
+            {
+                recent.push((idx, tokens));
+                image_tokens += tokens;
+            } else {
+                retrieval_candidates.push((idx, tokens));
+            }
+        }
+        // the oldest screenshots leave the window CACHE_WINDOW_STEP at a time rather than one per
+        // new screenshot, so the start of the prompt stays the same and can be cached
+        if !retrieval_candidates.is_empty() {
+            let extra = (CACHE_WINDOW_STEP - retrieval_candidates.len() % CACHE_WINDOW_STEP)
+                % CACHE_WINDOW_STEP;
+            let moved = recent.split_off(recent.len() - extra.min(recent.len()));
+            image_tokens -= moved.iter().map(|(_, tokens)| tokens).sum::<u32>();
+            retrieval_candidates.splice(0..0, moved);
+        }
+        for &(idx, _) in &recent {
+            included[idx] = true;
+        }
+        let mut num_images = recent.len();
+        let mut retrieved = vec![false; events.len()];
         if let Some(query) = query_for_retrieval {
-            if retrieval_corpus.len() > MAX_NUM_RETRIEVED_IMAGES_PER_LLM_CALL {
-                let top_k_relevant_images = match dense_embedding_search(
+            // screenshots without an embedding yet cannot be retrieved
+            let retrieval_corpus: Vec<EmbeddedDocument<(usize, u32)>> = retrieval_candidates
+                .iter()
+                .filter_map(|&(idx, tokens)| match &events[idx] {
+                    Event::Screenshot(screenshot_event) => screenshot_event
+                        .text_embedding
+                        .as_ref()
+                        .map(|embedding| EmbeddedDocument {
+                            embedding,
+                            document: (idx, tokens),
+                        }),
+                    _ => None,
+                })
+                .collect();
+            let corpus_tokens: u32 = retrieval_corpus.iter().map(|doc| doc.document.1).sum();
+            let ranked_idxs: Vec<(usize, u32)> = if image_tokens + corpus_tokens <= image_budget
+                && num_images + retrieval_corpus.len() <= max_images
+            {
+                retrieval_corpus.iter().map(|doc| doc.document).collect()
+            } else {
+                match dense_embedding_search(
                     query,
                     &retrieval_corpus,
-                    MAX_NUM_RETRIEVED_IMAGES_PER_LLM_CALL,
+                    retrieval_corpus.len(),
+                    Purpose::Chat,
                 )
                 .await
                 {
-                    Ok(top_k_relevant_images) => top_k_relevant_images,
+                    Ok(results) => results
+                        .into_iter()
+                        .map(|result| result.embedded_document.document)
+                        .collect(),
                     Err(e) => return Err(BuildMessagesError::RetrievalError(e)),
-                };
-                top_k_relevant_images.into_iter().for_each(|image| {
-                    messages_rev.insert(
-                        messages_rev.len() - 1,
-                        image.embedded_document.document.to_llm_message(None),
-                    );
-                });
-            } else {
-                retrieval_corpus.into_iter().for_each(|image| {
-                    messages_rev
-                        .insert(messages_rev.len() - 1, image.document.to_llm_message(None));
-                });
+                }
+            };
+            for (idx, tokens) in ranked_idxs {
+                if num_images >= max_images {
+                    break;
+                }
+                if image_tokens + tokens > image_budget {
+                    continue;
+                }
+                retrieved[idx] = true;
+                image_tokens += tokens;
+                num_images += 1;
+            }
+        }
+
+        // the retrieved screenshots change with every query, so they go after the part of the
+        // prompt that is repeated between calls, just before the newest message; their captions
+        // keep the times they were taken
+        let mut stable: Vec<usize> = (0..events.len()).filter(|&idx| included[idx]).collect();
+        let newest_message = match stable.last() {
+            Some(&idx) if matches!(events[idx], Event::Message(_)) => stable.pop(),
+            _ => None,
+        };
+        // the screenshots left out of the window are a multiple of CACHE_WINDOW_STEP, so every
+        // CACHE_WINDOW_STEPth screenshot in it is the same screenshot from one call to the next
+        let mut cache_breakpoints: Vec<usize> = Vec::new();
+        let mut num_screenshots = 0;
+        for (message_idx, &idx) in stable.iter().enumerate() {
+            if let Event::Screenshot(_) = &events[idx] {
+                num_screenshots += 1;
+                if num_screenshots % CACHE_WINDOW_STEP == 0 {
+                    cache_breakpoints.push(message_idx);
+                }
             }
         }
-        messages_rev.reverse();
-        Ok(messages_rev)
+        if let Some(last) = stable.len().checked_sub(1) {
+            cache_breakpoints.push(last);
+        }
+        cache_breakpoints.dedup();
+        let cache_breakpoints = cache_breakpoints.split_off(
+            cache_breakpoints
+                .len()
+                .saturating_sub(MAX_CACHE_BREAKPOINTS),
+        );
+
+        let order: Vec<usize> = stable
+            .into_iter()
+            .chain((0..events.len()).filter(|&idx| retrieved[idx]))
+            .chain(newest_message)
+            .collect();
+        let mut image_descriptions = HashMap::new();
+        let mut recent_screenshots = Vec::new();
+        let mut image_drop_order = Vec::new();
+        for (message_idx, &idx) in order.iter().enumerate() {
+            if let Event::Screenshot(screenshot_event) = &events[idx] {
+                if let Some(description) = &screenshot_event.text_description {
+                    image_descriptions.insert(message_idx, description.clone());
+                }
+                if retrieved[idx] {
+                    image_drop_order.push(message_idx);
+                } else {
+                    recent_screenshots.push(message_idx);
+                }
+            }
+        }
+        image_drop_order.extend(recent_screenshots);
+        let messages = order
+            .into_iter()
+            .map(|idx| match &events[idx] {
+                Event::Message(message) => message.clone(),
+                Event::Screenshot(screenshot_event) => {
+                    screenshot_event.screenshot.to_llm_message(None)
+                }
+            })
+            .collect();
+        Ok(BuiltMessages {
+            messages,
+            cache_breakpoints,
+            image_descriptions,
+            image_drop_order,
+        })
     }
 }
+
+fn screenshot_tokens(provider: &Provider, screenshot: &Screenshot) -> u32 {
+    estimate_image_tokens(
+        provider,
+        screenshot.image.width(),
+        screenshot.image.height(),
+    ) + SCREENSHOT_CAPTION_TOKENS
+}